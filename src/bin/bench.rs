@@ -0,0 +1,66 @@
+use std::{
+    env, fs,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use lunatic::{Lunatic, Preemption, Val};
+
+/// Module started by default, `example` built for `wasm32-unknown-unknown`.
+const EXAMPLE: &str = "example/target/wasm32-unknown-unknown/release/lunar.wasm";
+
+/// Starts `n` processes calling `hello` of the module with the given kind of
+/// preemption, returns how long it took until they all ended.
+async fn bench(preemption: Preemption, bytes: &[u8], n: i64) -> Result<Duration> {
+    let wat = r#"
+        (module
+            (import "host" "hello" (func $host_hello (param i32)))
+
+            (func (export "hello")
+                i32.const 3
+                call $host_hello)
+        )
+    "#;
+    let (lunatic, runner) = Lunatic::builder().preemption(preemption).build()?;
+    let runner = tokio::spawn(runner);
+
+    let _module = lunatic.load(wat)?;
+    let module = lunatic.load_async(bytes).await?;
+    let started_at = Instant::now();
+    let mut handles = Vec::with_capacity(n as usize);
+    for i in 0..n {
+        handles.push(lunatic.start_async(module, "hello", &[Val::I64(i)]).await?);
+    }
+    let mut failed = 0;
+    for handle in handles {
+        let id = handle.id();
+        if let Err(error) = handle.await {
+            println!("Process {} failed: {}", id, error);
+            failed += 1;
+        }
+    }
+    let duration = started_at.elapsed();
+    println!("{:?}: ended {}, {} failed", preemption, n, failed);
+
+    lunatic.shutdown_handle().shutdown(None);
+    let summary = runner.await?;
+    println!("Shut down, {} processes killed", summary.killed);
+    Ok(duration)
+}
+
+/// Runs the example module, or the `.wasm` or `.wat` file given as the first
+/// argument, once with fuel metering and once with epochs.
+#[tokio::main(flavor = "multi_thread")]
+async fn main() -> Result<()> {
+    let path = env::args().nth(1).unwrap_or_else(|| EXAMPLE.to_string());
+    let bytes = fs::read(&path).with_context(|| format!("failed to read {}", path))?;
+    let n = 3000;
+    for preemption in [
+        Preemption::Fuel,
+        Preemption::Epoch(Duration::from_millis(1)),
+    ] {
+        let duration = bench(preemption, &bytes, n).await?;
+        println!("Total duration {}ms", duration.as_millis());
+    }
+    Ok(())
+}
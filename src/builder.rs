@@ -0,0 +1,399 @@
+use std::{
+    any::Any,
+    collections::hash_map::DefaultHasher,
+    future::Future,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use tokio::sync::{mpsc, oneshot, Notify, Semaphore};
+use wasmtime::{Engine, Linker, OptLevel};
+
+use crate::{
+    cache::ModuleCache,
+    host::{self, HostApi},
+    runner::{self, Admission, ShutdownSummary},
+    ExtensionFn, Lunatic, LunaticError, LunaticInner, ModuleId, ProcessConfig, ProcessId,
+    ProcessState, Result, HOST,
+};
+
+type RegisterFn = Box<dyn FnOnce(&mut Linker<ProcessState>) -> anyhow::Result<()>>;
+
+/// Processes that can wait for the runner, if not configured.
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// Ended processes whose status is kept, if not configured.
+const DEFAULT_HISTORY_LEN: usize = 1024;
+
+/// How running processes are made to yield to others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Preemption {
+    /// Guests count the fuel they consume and yield after each
+    /// `ProcessConfig::fuel_slice`. Allows fuel budgets, but the counting
+    /// slows compute-heavy guests down.
+    #[default]
+    Fuel,
+    /// A ticker thread advances wasmtime's epoch every given interval and
+    /// processes yield whenever it advanced. Cheaper than fuel, but settings
+    /// with `ProcessConfig::fuel` or `ProcessConfig::fuel_slice` are refused
+    /// with `LunaticError::Config`.
+    Epoch(Duration),
+}
+
+/// Configures the engine and defaults of a `Lunatic` runtime.
+pub struct LunaticBuilder {
+    opt_level: OptLevel,
+    preemption: Preemption,
+    async_stack_size: Option<usize>,
+    parallel_compilation: bool,
+    cache: bool,
+    cache_config: Option<PathBuf>,
+    module_cache: Option<PathBuf>,
+    default_config: ProcessConfig,
+    host_apis: Vec<HostApi>,
+    host_functions: Vec<RegisterFn>,
+    extension: Option<ExtensionFn>,
+    queue_capacity: usize,
+    max_running: Option<usize>,
+    admission: Admission,
+    history_len: usize,
+}
+
+impl Default for LunaticBuilder {
+    fn default() -> Self {
+        Self {
+            opt_level: OptLevel::Speed,
+            preemption: Preemption::default(),
+            async_stack_size: None,
+            parallel_compilation: true,
+            cache: false,
+            cache_config: None,
+            module_cache: None,
+            default_config: ProcessConfig::default(),
+            host_apis: HostApi::ALL.to_vec(),
+            host_functions: Vec::new(),
+            extension: None,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            max_running: None,
+            admission: Admission::default(),
+            history_len: DEFAULT_HISTORY_LEN,
+        }
+    }
+}
+
+impl LunaticBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Optimization level of Cranelift, `OptLevel::Speed` by default.
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    /// How processes are preempted, `Preemption::Fuel` by default.
+    pub fn preemption(mut self, preemption: Preemption) -> Self {
+        self.preemption = preemption;
+        self
+    }
+
+    /// Stack size available to processes, including host functions they call.
+    pub fn async_stack_size(mut self, size: usize) -> Self {
+        self.async_stack_size = Some(size);
+        self
+    }
+
+    /// Compiles the functions of a module on multiple threads, enabled by default.
+    pub fn parallel_compilation(mut self, enabled: bool) -> Self {
+        self.parallel_compilation = enabled;
+        self
+    }
+
+    /// Enables wasmtime's compilation cache with its default configuration.
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled;
+        self
+    }
+
+    /// Enables wasmtime's compilation cache, configured by the file at `path`.
+    pub fn cache_config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cache = true;
+        self.cache_config = Some(path.into());
+        self
+    }
+
+    /// Stores modules compiled by `Lunatic::load` in the directory at `path`
+    /// and loads them from there instead of compiling them again.
+    ///
+    /// Artifacts are kept apart per engine configuration, changing the
+    /// configuration or upgrading the runtime never loads stale code. The
+    /// directory must only be writable by trusted users, its contents get
+    /// executed.
+    pub fn module_cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.module_cache = Some(path.into());
+        self
+    }
+
+    /// Process settings used where neither the module nor `start_with` set them.
+    pub fn default_config(mut self, config: ProcessConfig) -> Self {
+        self.default_config = config;
+        self
+    }
+
+    /// Host functions guests can import, all of them by default.
+    pub fn host_apis(mut self, apis: &[HostApi]) -> Self {
+        self.host_apis.clear();
+        for api in apis {
+            if !self.host_apis.contains(api) {
+                self.host_apis.push(*api);
+            }
+        }
+        self
+    }
+
+    /// Registers additional host functions guests can import.
+    ///
+    /// `register` gets the runtime's linker, sync functions are added with
+    /// `Linker::func_wrap`, async ones with `Linker::func_wrap_async`. Through
+    /// the `Caller` they can access the calling process' `ProcessState` and,
+    /// with `host::memory`, its linear memory. Modules are checked against all
+    /// registered functions when they are loaded.
+    pub fn host_functions(
+        mut self,
+        register: impl FnOnce(&mut Linker<ProcessState>) -> anyhow::Result<()> + 'static,
+    ) -> Self {
+        self.host_functions.push(Box::new(register));
+        self
+    }
+
+    /// Sets the function creating the extension slot of every `ProcessState`,
+    /// for embedder-defined per-process data.
+    pub fn extension<T: Any + Send>(
+        mut self,
+        init: impl Fn(ProcessId, ModuleId) -> T + Send + Sync + 'static,
+    ) -> Self {
+        self.extension = Some(Box::new(move |process_id, module_id| {
+            Box::new(init(process_id, module_id)) as Box<dyn Any + Send>
+        }));
+        self
+    }
+
+    /// Number of started processes that can wait for the runner, 1024 by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    /// Maximum number of processes running at once, unlimited by default.
+    ///
+    /// Further processes stay queued until a running one ends.
+    pub fn max_running(mut self, max: usize) -> Self {
+        self.max_running = Some(max.max(1));
+        self
+    }
+
+    /// What happens to processes started while the queue is full,
+    /// `Admission::Wait` by default.
+    pub fn admission(mut self, admission: Admission) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Number of ended processes whose status stays available through
+    /// `Lunatic::status`, 1024 by default. The records of the processes that
+    /// ended first are dropped first.
+    pub fn history(mut self, len: usize) -> Self {
+        self.history_len = len;
+        self
+    }
+
+    /// Creates the runtime and the runner future, which has to be polled for
+    /// processes to make progress. It resolves after a shutdown was requested
+    /// through a `ShutdownHandle`, or once all handles to the runtime were
+    /// dropped and its processes ended.
+    pub fn build(self) -> Result<(Lunatic, impl Future<Output = ShutdownSummary>)> {
+        let config_error = |error: anyhow::Error| LunaticError::Config(error.to_string());
+        let link_error = |error: anyhow::Error| LunaticError::LinkError(error.to_string());
+        let mut config = wasmtime::Config::new();
+        config
+            .async_support(true)
+            .consume_fuel(self.preemption == Preemption::Fuel)
+            .epoch_interruption(self.preemption != Preemption::Fuel)
+            .parallel_compilation(self.parallel_compilation)
+            .cranelift_opt_level(self.opt_level);
+        if let Some(size) = self.async_stack_size {
+            config.async_stack_size(size);
+        }
+        match (self.cache, self.cache_config) {
+            (true, Some(path)) => {
+                config.cache_config_load(path).map_err(config_error)?;
+            }
+            (true, None) => {
+                config.cache_config_load_default().map_err(config_error)?;
+            }
+            (false, _) => (),
+        }
+
+        let engine = Engine::new(&config).map_err(config_error)?;
+        // Wasmtime's compatibility hash covers its version, the target and the
+        // compiler settings, the runtime's own settings are added on top.
+        let mut hasher = DefaultHasher::new();
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        let preemption = match self.preemption {
+            Preemption::Fuel => "fuel",
+            Preemption::Epoch(_) => "epoch",
+        };
+        let fingerprint = format!(
+            "lunatic {:016x} {:?} {}",
+            hasher.finish(),
+            self.opt_level,
+            preemption
+        );
+        let module_cache = self
+            .module_cache
+            .map(|path| ModuleCache::new(&path, &fingerprint));
+        let mut linker = Linker::new(&engine);
+        host::add_to_linker(&mut linker, &self.host_apis).map_err(link_error)?;
+        for register in self.host_functions {
+            register(&mut linker).map_err(link_error)?;
+        }
+
+        let (sender, receiver) = mpsc::channel(self.queue_capacity);
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+
+        let inner = Arc::new(LunaticInner {
+            next_module_id: AtomicU64::new(0),
+            // Process ids start at 1, `HOST` is reserved for the host's mailbox.
+            next_process_id: AtomicU64::new(HOST + 1),
+            modules: Default::default(),
+            instance_pre: Default::default(),
+            module_sections: Default::default(),
+            repository: Default::default(),
+            module_usage: Default::default(),
+            processes: Default::default(),
+            history: Default::default(),
+            history_len: self.history_len,
+            mailboxes: Default::default(),
+            names: Default::default(),
+            module_configs: Default::default(),
+            default_config: RwLock::new(self.default_config),
+            engine,
+            preemption: self.preemption,
+            linker,
+            sender,
+            queue_capacity: self.queue_capacity,
+            admission: self.admission,
+            running_slots: self.max_running.map(|max| Arc::new(Semaphore::new(max))),
+            max_running: self.max_running,
+            running: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            extension: self.extension,
+            module_cache,
+            accepting: AtomicBool::new(true),
+            shutdown: Mutex::new(Some(shutdown)),
+            // The `Lunatic` returned below.
+            handles: AtomicUsize::new(1),
+            ended: Notify::new(),
+        });
+        inner.mailboxes.insert(HOST, Default::default());
+        if let Preemption::Epoch(interval) = self.preemption {
+            let inner = Arc::downgrade(&inner);
+            thread::spawn(move || tick(inner, interval));
+        }
+
+        let task = runner::run(inner.clone(), receiver, shutdown_receiver);
+
+        Ok((Lunatic { inner }, task))
+    }
+}
+
+/// Advances the epoch of the runtime's engine every `interval`, until the
+/// runtime is dropped.
+fn tick(lunatic: Weak<LunaticInner>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match lunatic.upgrade() {
+            Some(lunatic) => lunatic.engine.increment_epoch(),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn extension_is_only_created_for_processes() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = created.clone();
+        let (lunatic, runner) = Lunatic::builder()
+            .extension(move |_, _| counter.fetch_add(1, Ordering::SeqCst))
+            .build()
+            .unwrap();
+        tokio::spawn(runner);
+        let module = lunatic.load(r#"(module (func (export "nop")))"#).unwrap();
+        assert_eq!(created.load(Ordering::SeqCst), 0);
+
+        lunatic.start(module, "nop", &[]).unwrap().await.unwrap();
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn epoch_preemption_yields_spinning_processes() {
+        let (lunatic, runner) = Lunatic::builder()
+            .preemption(Preemption::Epoch(Duration::from_millis(1)))
+            .build()
+            .unwrap();
+        tokio::spawn(runner);
+        let module = lunatic
+            .load(r#"(module (func (export "spin") (loop br 0)) (func (export "nop")))"#)
+            .unwrap();
+        // The test runs on a single thread, the second process only gets to
+        // run if the first one yields.
+        let spinning = lunatic.start(module, "spin", &[]).unwrap();
+        let process = lunatic.start(module, "nop", &[]).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), process).await;
+        assert!(matches!(result, Ok(Ok(_))));
+        assert!(lunatic.kill(spinning.id()));
+    }
+
+    #[tokio::test]
+    async fn epoch_preemption_refuses_fuel_settings() {
+        let (lunatic, runner) = Lunatic::builder()
+            .preemption(Preemption::Epoch(Duration::from_millis(1)))
+            .build()
+            .unwrap();
+        tokio::spawn(runner);
+        let wat = r#"(module (func (export "nop")))"#;
+        let fuel = ProcessConfig {
+            fuel: Some(1000),
+            ..Default::default()
+        };
+        let fuel_slice = ProcessConfig {
+            fuel_slice: Some(100),
+            ..Default::default()
+        };
+        assert!(matches!(
+            lunatic.load_with(wat, fuel.clone()),
+            Err(LunaticError::Config(_))
+        ));
+        let module = lunatic.load(wat).unwrap();
+        for config in &[fuel, fuel_slice] {
+            assert!(matches!(
+                lunatic.start_with(module, "nop", &[], config),
+                Err(LunaticError::Config(_))
+            ));
+        }
+        lunatic.start(module, "nop", &[]).unwrap().await.unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+use wasmtime::{Engine, Module};
+
+/// Length of the checksum stored in front of every artifact.
+const CHECKSUM_LEN: usize = 32;
+
+/// Content-addressed directory of compiled modules.
+///
+/// Artifacts live in a subdirectory named after the fingerprint of the engine
+/// configuration, so a runtime configured differently never picks up code
+/// compiled for another configuration. Each file is named after the hash of
+/// the wasm it was compiled from and starts with a checksum of the artifact,
+/// files that are damaged or refused by wasmtime are recompiled and replaced.
+///
+/// Deserializing runs the machine code in the files, the directory must only
+/// be writable by trusted users.
+pub(crate) struct ModuleCache {
+    dir: PathBuf,
+}
+
+// Distinguishes temporary files of concurrent writes within a process.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+impl ModuleCache {
+    pub(crate) fn new(root: &Path, fingerprint: &str) -> Self {
+        Self {
+            dir: root.join(hex(&Sha256::digest(fingerprint.as_bytes()))),
+        }
+    }
+
+    /// Returns the cached module compiled from `bytes`, compiling and storing
+    /// it on a miss.
+    pub(crate) fn load(&self, engine: &Engine, bytes: &[u8]) -> anyhow::Result<Module> {
+        let path = self.path(bytes);
+        if let Some(module) = self.read(engine, &path) {
+            return Ok(module);
+        }
+        let module = Module::new(engine, bytes)?;
+        // The cache is an optimization, failing to fill it doesn't fail the load.
+        if let Ok(artifact) = module.serialize() {
+            self.write(&path, &artifact).ok();
+        }
+        Ok(module)
+    }
+
+    /// The file holding the artifact compiled from `bytes`.
+    fn path(&self, bytes: &[u8]) -> PathBuf {
+        self.dir
+            .join(format!("{}.cwasm", hex(&Sha256::digest(bytes))))
+    }
+
+    fn read(&self, engine: &Engine, path: &Path) -> Option<Module> {
+        let file = fs::read(path).ok()?;
+        let valid = file.len() > CHECKSUM_LEN
+            && Sha256::digest(&file[CHECKSUM_LEN..])[..] == file[..CHECKSUM_LEN];
+        // Only artifacts written by `write` with an intact checksum get here, wasmtime
+        // itself refuses ones made by another version or for other compiler settings.
+        let module = if valid {
+            unsafe { Module::deserialize(engine, &file[CHECKSUM_LEN..]) }.ok()
+        } else {
+            None
+        };
+        if module.is_none() {
+            fs::remove_file(path).ok();
+        }
+        module
+    }
+
+    /// Writes through a temporary file, readers never see a partial artifact.
+    fn write(&self, path: &Path, artifact: &[u8]) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let temp = self.dir.join(format!(
+            ".{}-{}.tmp",
+            process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = Sha256::digest(artifact).to_vec();
+        file.extend_from_slice(artifact);
+        fs::write(&temp, file)?;
+        let renamed = fs::rename(&temp, path);
+        if renamed.is_err() {
+            fs::remove_file(&temp).ok();
+        }
+        renamed
+    }
+
+    /// Removes all artifacts compiled with the current engine configuration.
+    pub(crate) fn clear(&self) -> std::io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Lunatic;
+
+    /// An empty directory for the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lunatic-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn wasm(export: &str) -> Vec<u8> {
+        wat::parse_str(format!(r#"(module (func (export "{}")))"#, export)).unwrap()
+    }
+
+    fn exports(module: &Module) -> Vec<&str> {
+        module.exports().map(|export| export.name()).collect()
+    }
+
+    #[test]
+    fn hit_loads_the_stored_artifact() {
+        let root = temp_dir("cache-hit");
+        let cache = ModuleCache::new(&root, "test");
+        let engine = Engine::default();
+        let (a, b) = (wasm("a"), wasm("b"));
+        cache.load(&engine, &a).unwrap();
+        cache.load(&engine, &b).unwrap();
+
+        // With the artifact of `b` in place of the one of `a`, a hit returns `b`.
+        fs::copy(cache.path(&b), cache.path(&a)).unwrap();
+        assert_eq!(exports(&cache.load(&engine, &a).unwrap()), ["b"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn corrupted_artifact_is_replaced() {
+        let root = temp_dir("cache-corrupted");
+        let cache = ModuleCache::new(&root, "test");
+        let engine = Engine::default();
+        let bytes = wasm("a");
+        cache.load(&engine, &bytes).unwrap();
+        let path = cache.path(&bytes);
+        let stored = fs::read(&path).unwrap();
+        let mut corrupted = stored.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        fs::write(&path, corrupted).unwrap();
+
+        assert_eq!(exports(&cache.load(&engine, &bytes).unwrap()), ["a"]);
+        assert_eq!(fs::read(&path).unwrap(), stored);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clear_module_cache_removes_artifacts() {
+        let root = temp_dir("cache-clear");
+        let (lunatic, _) = Lunatic::builder().module_cache(&root).build().unwrap();
+        lunatic.load(wasm("a")).unwrap();
+        let dir = fs::read_dir(&root).unwrap().next().unwrap().unwrap().path();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        lunatic.clear_module_cache().unwrap();
+        assert!(!dir.exists());
+        // Clearing an empty cache succeeds, and loading fills it again.
+        lunatic.clear_module_cache().unwrap();
+        lunatic.load(wasm("a")).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
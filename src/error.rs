@@ -0,0 +1,177 @@
+use std::{error::Error, fmt, time::Duration};
+
+use wasmtime::ValType;
+
+use crate::{ModuleId, ProcessId, ProcessStatus};
+
+/// Why an operation of the runtime failed, or why a process ended abnormally.
+#[derive(Debug, Clone)]
+pub enum LunaticError {
+    UnknownModule(ModuleId),
+    /// The module has processes that haven't ended yet.
+    ModuleInUse {
+        module_id: ModuleId,
+        processes: usize,
+    },
+    /// The module is unloaded as soon as its processes ended, new ones can't be started.
+    ModuleUnloading(ModuleId),
+    /// No module is loaded under the name, or the version of it.
+    UnknownModuleName(String),
+    /// Another module was already loaded as `name@version`.
+    ModuleNameTaken(String),
+    /// Names and versions can't be empty or contain `@`.
+    InvalidModuleName(String),
+    /// A module file or directory couldn't be read.
+    Io(String),
+    /// The process doesn't exist or has ended.
+    UnknownProcess(ProcessId),
+    /// The bytes are not a valid module.
+    CompileError(String),
+    /// An import isn't provided by the host, or host functions couldn't be registered.
+    LinkError(String),
+    MissingExport {
+        module_id: ModuleId,
+        function: String,
+    },
+    SignatureMismatch {
+        function: String,
+        expected: Vec<ValType>,
+        given: Vec<ValType>,
+    },
+    NameTaken {
+        name: String,
+        process_id: ProcessId,
+    },
+    /// The engine configuration was refused by wasmtime.
+    Config(String),
+    /// The spawn queue is full and the admission policy doesn't wait.
+    QueueFull,
+    ShuttingDown,
+    /// The runner future was dropped, processes can't be started anymore.
+    RunnerStopped,
+    /// A supervisor gave up after too many restarts of `child`.
+    RestartIntensity {
+        child: usize,
+        status: ProcessStatus,
+        max_restarts: usize,
+        period: Duration,
+    },
+    FailedToInstantiate(String),
+    Trap {
+        message: String,
+        backtrace: Vec<String>,
+    },
+    /// The process called WASI's `proc_exit`, or a host function returned
+    /// `host::Exit`, with a non-zero exit code.
+    Exited(i32),
+    LimitExceeded(String),
+    OutOfFuel,
+    Killed,
+    /// The spawn queue was full when the process was started.
+    Rejected,
+}
+
+impl LunaticError {
+    /// Turns the status of a process that ended abnormally into an error.
+    pub(crate) fn from_status(status: ProcessStatus) -> Self {
+        match status {
+            ProcessStatus::FailedToInstantiate(reason) => LunaticError::FailedToInstantiate(reason),
+            ProcessStatus::Trapped { message, backtrace } => {
+                LunaticError::Trap { message, backtrace }
+            }
+            ProcessStatus::Exited(code) => LunaticError::Exited(code),
+            ProcessStatus::LimitExceeded(reason) => LunaticError::LimitExceeded(reason),
+            ProcessStatus::OutOfFuel => LunaticError::OutOfFuel,
+            ProcessStatus::Rejected => LunaticError::Rejected,
+            // Pending, running and finished processes don't produce errors,
+            // being dropped by the runner is as good as being killed.
+            _ => LunaticError::Killed,
+        }
+    }
+}
+
+impl fmt::Display for LunaticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LunaticError::UnknownModule(module_id) => {
+                write!(f, "module {} is not loaded", module_id)
+            }
+            LunaticError::ModuleInUse {
+                module_id,
+                processes,
+            } => write!(
+                f,
+                "module {} is still used by {} processes",
+                module_id, processes
+            ),
+            LunaticError::ModuleUnloading(module_id) => {
+                write!(f, "module {} is being unloaded", module_id)
+            }
+            LunaticError::UnknownModuleName(spec) => write!(f, "module {} is not loaded", spec),
+            LunaticError::ModuleNameTaken(spec) => write!(f, "module {} is already loaded", spec),
+            LunaticError::InvalidModuleName(spec) => write!(f, "invalid module name {}", spec),
+            LunaticError::Io(reason) => write!(f, "{}", reason),
+            LunaticError::UnknownProcess(process_id) => {
+                write!(f, "process {} doesn't exist or has ended", process_id)
+            }
+            LunaticError::CompileError(reason) => write!(f, "failed to compile: {}", reason),
+            LunaticError::LinkError(reason) => write!(f, "failed to link: {}", reason),
+            LunaticError::MissingExport {
+                module_id,
+                function,
+            } => write!(
+                f,
+                "module {} has no function export {}",
+                module_id, function
+            ),
+            LunaticError::SignatureMismatch {
+                function,
+                expected,
+                given,
+            } => write!(
+                f,
+                "function {} expects parameters {:?}, got {:?}",
+                function, expected, given
+            ),
+            LunaticError::NameTaken { name, process_id } => write!(
+                f,
+                "name {} is already taken by process {}",
+                name, process_id
+            ),
+            LunaticError::Config(reason) => write!(f, "invalid configuration: {}", reason),
+            LunaticError::QueueFull => write!(f, "spawn queue is full"),
+            LunaticError::ShuttingDown => write!(f, "runtime is shutting down"),
+            LunaticError::RunnerStopped => write!(f, "runner is not running"),
+            LunaticError::RestartIntensity {
+                child,
+                status,
+                max_restarts,
+                period,
+            } => write!(
+                f,
+                "child {} ended with \"{}\", restart intensity of {} in {:?} exceeded",
+                child, status, max_restarts, period
+            ),
+            LunaticError::FailedToInstantiate(reason) => {
+                write!(f, "failed to instantiate: {}", reason)
+            }
+            LunaticError::Trap { message, backtrace } => {
+                write!(f, "trapped: {}", message)?;
+                for frame in backtrace {
+                    write!(f, "\n    {}", frame)?;
+                }
+                Ok(())
+            }
+            LunaticError::Exited(code) => write!(f, "exited with code {}", code),
+            LunaticError::LimitExceeded(reason) => write!(f, "limit exceeded: {}", reason),
+            LunaticError::OutOfFuel => write!(f, "out of fuel"),
+            LunaticError::Killed => write!(f, "killed"),
+            LunaticError::Rejected => write!(f, "rejected, the spawn queue is full"),
+        }
+    }
+}
+
+impl Error for LunaticError {}
+
+/// Result of the runtime's operations.
+pub type Result<T, E = LunaticError> = std::result::Result<T, E>;
@@ -0,0 +1,272 @@
+//! Host functions available to guests, and helpers for embedders writing their own.
+
+use std::{error::Error, fmt, future::Future, time::Duration};
+
+use anyhow::{anyhow, Result};
+use wasmtime::*;
+
+use crate::{mailbox::Message, LunaticError, ModuleId, ProcessId, ProcessState};
+
+/// Groups of host functions that can be made available to guests, all of
+/// them are imported from the `host` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HostApi {
+    /// `send`, `receive`, `message_kind` and `message_sender`.
+    Messaging,
+    /// `spawn`, `link`, `unlink`, `monitor` and `demonitor`.
+    Processes,
+    /// `register`, `whereis` and `unregister`.
+    Registry,
+}
+
+impl HostApi {
+    pub const ALL: [HostApi; 3] = [HostApi::Messaging, HostApi::Processes, HostApi::Registry];
+}
+
+/// Adds `host.hello`, WASI's `proc_exit` and the host functions of `apis` to
+/// the linker.
+pub(crate) fn add_to_linker(linker: &mut Linker<ProcessState>, apis: &[HostApi]) -> Result<()> {
+    linker.func_wrap(
+        "host",
+        "hello",
+        |_caller: Caller<'_, ProcessState>, _param: i32| {
+            //println!("Got {} from WebAssembly", param);
+            //println!("my host state is: {:?}", caller.data());
+        },
+    )?;
+    // The only WASI function provided, so commands can end with an exit code.
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "proc_exit",
+        |code: i32| -> Result<()> { Err(Exit(code).into()) },
+    )?;
+    for api in apis {
+        match api {
+            HostApi::Messaging => add_messaging(linker)?,
+            HostApi::Processes => add_processes(linker)?,
+            HostApi::Registry => add_registry(linker)?,
+        }
+    }
+    Ok(())
+}
+
+fn add_messaging(linker: &mut Linker<ProcessState>) -> Result<()> {
+    linker.func_wrap("host", "send", send)?;
+    linker.func_wrap_async("host", "receive", receive)?;
+    linker.func_wrap(
+        "host",
+        "message_kind",
+        |caller: Caller<'_, ProcessState>| caller.data().last_kind,
+    )?;
+    linker.func_wrap(
+        "host",
+        "message_sender",
+        |caller: Caller<'_, ProcessState>| caller.data().last_sender,
+    )?;
+    Ok(())
+}
+
+fn add_processes(linker: &mut Linker<ProcessState>) -> Result<()> {
+    linker.func_wrap("host", "spawn", spawn)?;
+    linker.func_wrap("host", "link", link)?;
+    linker.func_wrap(
+        "host",
+        "unlink",
+        |caller: Caller<'_, ProcessState>, to: ProcessId| {
+            let state = caller.data();
+            state.runtime.unlink(state.id, to);
+        },
+    )?;
+    linker.func_wrap(
+        "host",
+        "monitor",
+        |caller: Caller<'_, ProcessState>, to: ProcessId| {
+            let state = caller.data();
+            state.runtime.monitor(state.id, to);
+        },
+    )?;
+    linker.func_wrap(
+        "host",
+        "demonitor",
+        |caller: Caller<'_, ProcessState>, to: ProcessId| {
+            let state = caller.data();
+            state.runtime.demonitor(state.id, to);
+        },
+    )?;
+    Ok(())
+}
+
+fn add_registry(linker: &mut Linker<ProcessState>) -> Result<()> {
+    linker.func_wrap("host", "register", register)?;
+    linker.func_wrap("host", "whereis", whereis)?;
+    linker.func_wrap("host", "unregister", unregister)?;
+    Ok(())
+}
+
+/// Error a host function returns to end the calling process with an exit code,
+/// like WASI's `proc_exit`. The process ends with `ProcessStatus::Exited`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exit(pub i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exited with code {}", self.0)
+    }
+}
+
+impl Error for Exit {}
+
+/// Returns the memory exported by the calling process' module as `memory`.
+pub fn memory(caller: &mut Caller<'_, ProcessState>) -> Result<Memory> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| anyhow!("module doesn't export a memory"))
+}
+
+/// Reads the UTF-8 string of `len` bytes at `ptr` from the caller's memory.
+pub fn read_string(caller: &mut Caller<'_, ProcessState>, ptr: u32, len: u32) -> Result<String> {
+    let memory = memory(caller)?;
+    let bytes = memory
+        .data(&*caller)
+        .get(ptr as usize..ptr as usize + len as usize)
+        .ok_or_else(|| anyhow!("string out of bounds"))?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| anyhow!("string is not UTF-8"))
+}
+
+/// Sends `len` bytes starting at `ptr` to the mailbox of process `to`.
+///
+/// Returns 0 on success and 1 if the process doesn't exist or has already ended.
+fn send(mut caller: Caller<'_, ProcessState>, to: ProcessId, ptr: u32, len: u32) -> Result<u32> {
+    let memory = memory(&mut caller)?;
+    // Checked before copying, `len` is up to the guest.
+    let data = memory
+        .data(&caller)
+        .get(ptr as usize..ptr as usize + len as usize)
+        .ok_or_else(|| anyhow!("message out of bounds"))?
+        .to_vec();
+    let state = caller.data();
+    let message = Message::Data {
+        from: state.id,
+        data,
+    };
+    if state.runtime.deliver(to, message) {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Waits for the next message and copies it into the buffer at `buf_ptr`.
+///
+/// A `timeout_ms` of 0 only checks for a message without waiting, like
+/// `Lunatic::receive` with a zero timeout, and `u64::MAX` (-1 as a signed
+/// integer) waits forever. Returns the size of the message, or -1 if no
+/// message arrived in time. If the message doesn't fit into the buffer,
+/// nothing is copied and the message stays in the mailbox, so the guest can
+/// retry with a buffer of the returned size. The kind and sender of a copied
+/// message are available through `message_kind` and `message_sender`.
+fn receive(
+    mut caller: Caller<'_, ProcessState>,
+    (buf_ptr, buf_len, timeout_ms): (u32, u32, u64),
+) -> Box<dyn Future<Output = Result<i64>> + Send + '_> {
+    Box::new(async move {
+        let memory = memory(&mut caller)?;
+        let mailbox = caller.data().mailbox.clone();
+        let timeout = match timeout_ms {
+            u64::MAX => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        let message = match mailbox.pop(timeout).await {
+            Some(message) => message,
+            None => return Ok(-1),
+        };
+        let payload = message.payload();
+        if payload.len() > buf_len as usize {
+            mailbox.push_front(message);
+            return Ok(payload.len() as i64);
+        }
+        memory
+            .write(&mut caller, buf_ptr as usize, &payload)
+            .map_err(|_| anyhow!("receive buffer out of bounds"))?;
+        let state = caller.data_mut();
+        state.last_kind = message.kind();
+        state.last_sender = message.sender();
+        Ok(payload.len() as i64)
+    })
+}
+
+/// Starts a process of `module_id`, calling the export named by the string at
+/// `name_ptr` with `arg` as its only argument.
+///
+/// Returns the id of the new process, or
+/// - -1 if the module isn't loaded, is being unloaded or doesn't export a
+///   function with this name taking a single `i64`,
+/// - -2 if the spawn queue is full, with `Admission::Reject` the id of a
+///   process that ended as rejected is returned instead,
+/// - -3 if the runtime is shutting down.
+fn spawn(
+    mut caller: Caller<'_, ProcessState>,
+    module_id: ModuleId,
+    name_ptr: u32,
+    name_len: u32,
+    arg: u64,
+) -> Result<i64> {
+    let name = read_string(&mut caller, name_ptr, name_len)?;
+    let runtime = &caller.data().runtime;
+    // The child runs detached, its result is only available through its status.
+    match runtime.start(
+        module_id,
+        &name,
+        &[Val::I64(arg as i64)],
+        &Default::default(),
+    ) {
+        Ok(child) => Ok(child.id() as i64),
+        Err(LunaticError::QueueFull) => Ok(-2),
+        Err(LunaticError::ShuttingDown | LunaticError::RunnerStopped) => Ok(-3),
+        Err(_) => Ok(-1),
+    }
+}
+
+/// Links the calling process with process `to`, if one of them fails the
+/// other one gets killed.
+///
+/// Returns 0 on success and 1 if process `to` has already ended.
+fn link(caller: Caller<'_, ProcessState>, to: ProcessId) -> u32 {
+    let state = caller.data();
+    if state.runtime.link(state.id, to) {
+        0
+    } else {
+        1
+    }
+}
+
+/// Registers the calling process under the name at `name_ptr`.
+///
+/// Returns 0 on success and 1 if the name is already taken.
+fn register(mut caller: Caller<'_, ProcessState>, name_ptr: u32, name_len: u32) -> Result<u32> {
+    let name = read_string(&mut caller, name_ptr, name_len)?;
+    let state = caller.data();
+    match state.runtime.register(name, state.id) {
+        Ok(()) => Ok(0),
+        Err(_) => Ok(1),
+    }
+}
+
+/// Looks up the process registered under the name at `name_ptr`.
+///
+/// Returns its id, or -1 if no process is registered under this name.
+fn whereis(mut caller: Caller<'_, ProcessState>, name_ptr: u32, name_len: u32) -> Result<i64> {
+    let name = read_string(&mut caller, name_ptr, name_len)?;
+    match caller.data().runtime.names.get(&name) {
+        Some(process_id) => Ok(*process_id as i64),
+        None => Ok(-1),
+    }
+}
+
+/// Removes the name at `name_ptr` from the registry, whichever process it points to.
+fn unregister(mut caller: Caller<'_, ProcessState>, name_ptr: u32, name_len: u32) -> Result<()> {
+    let name = read_string(&mut caller, name_ptr, name_len)?;
+    caller.data().runtime.unregister(&name);
+    Ok(())
+}
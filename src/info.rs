@@ -0,0 +1,210 @@
+use std::{collections::BTreeMap, convert::TryFrom};
+
+use wasmparser::{KnownCustom, Name, NameSectionReader, Parser, Payload};
+use wasmtime::{ExternType, FuncType, MemoryType, Module, RefType, TableType};
+
+/// Description of a loaded module, returned by `Lunatic::module_info`.
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    /// Name from the module's name section.
+    pub name: Option<String>,
+    pub imports: Vec<ImportInfo>,
+    pub exports: Vec<ExportInfo>,
+    /// All memories in index order, imported ones first.
+    pub memories: Vec<MemoryType>,
+    /// All tables in index order, imported ones first.
+    pub tables: Vec<TableType>,
+    /// Custom sections in the order they appear in the module, including
+    /// the name section.
+    pub custom_sections: Vec<CustomSection>,
+    /// Function names from the module's name section, by function index.
+    pub function_names: BTreeMap<u32, String>,
+}
+
+impl ModuleInfo {
+    /// Returns the signature of the exported function `name`.
+    pub fn function(&self, name: &str) -> Option<&FuncType> {
+        self.exports
+            .iter()
+            .find(|export| export.name == name)
+            .and_then(|export| export.ty.func())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportInfo {
+    pub module: String,
+    pub name: String,
+    pub ty: ExternType,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportInfo {
+    pub name: String,
+    pub ty: ExternType,
+}
+
+/// Contents of custom sections beyond this size aren't kept.
+const MAX_CUSTOM_SECTION_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomSection {
+    pub name: String,
+    /// The contents, cut off after the first 64 KiB to bound the memory a
+    /// loaded module keeps.
+    pub data: Vec<u8>,
+    /// Size of the complete contents, larger than `data.len()` if cut off.
+    pub len: usize,
+}
+
+/// Parts of a module wasmtime doesn't keep, read from its bytes at load time.
+#[derive(Default)]
+pub(crate) struct Sections {
+    memories: Vec<MemoryType>,
+    tables: Vec<TableType>,
+    custom_sections: Vec<CustomSection>,
+    function_names: BTreeMap<u32, String>,
+}
+
+impl Sections {
+    /// Reads the sections of a module in binary or text format that already
+    /// compiled, anything that can't be read is left out.
+    pub(crate) fn parse(bytes: &[u8]) -> Self {
+        let mut sections = Sections::default();
+        let binary = match wat::parse_bytes(bytes) {
+            Ok(binary) => binary,
+            Err(_) => return sections,
+        };
+        for payload in Parser::new(0).parse_all(&binary) {
+            match payload {
+                Ok(Payload::MemorySection(reader)) => {
+                    sections.memories.extend(
+                        reader
+                            .into_iter()
+                            .filter_map(|memory| memory_type(memory.ok()?)),
+                    );
+                }
+                Ok(Payload::TableSection(reader)) => {
+                    sections.tables.extend(
+                        reader
+                            .into_iter()
+                            .filter_map(|table| table_type(table.ok()?.ty)),
+                    );
+                }
+                Ok(Payload::CustomSection(reader)) => {
+                    if let KnownCustom::Name(names) = reader.as_known() {
+                        sections.read_names(names);
+                    }
+                    let data = reader.data();
+                    sections.custom_sections.push(CustomSection {
+                        name: reader.name().to_string(),
+                        data: data[..data.len().min(MAX_CUSTOM_SECTION_LEN)].to_vec(),
+                        len: data.len(),
+                    });
+                }
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+        sections
+    }
+
+    fn read_names(&mut self, reader: NameSectionReader<'_>) {
+        for name in reader {
+            match name {
+                Ok(Name::Function(names)) => {
+                    for naming in names {
+                        match naming {
+                            Ok(naming) => {
+                                self.function_names
+                                    .insert(naming.index, naming.name.to_string());
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Describes `module`, with `sections` if its bytes were available when it
+/// got loaded.
+pub(crate) fn module_info(module: &Module, sections: Option<&Sections>) -> ModuleInfo {
+    let imports = module
+        .imports()
+        .map(|import| ImportInfo {
+            module: import.module().to_string(),
+            name: import.name().to_string(),
+            ty: import.ty(),
+        })
+        .collect::<Vec<_>>();
+    let exports = module
+        .exports()
+        .map(|export| ExportInfo {
+            name: export.name().to_string(),
+            ty: export.ty(),
+        })
+        .collect();
+    let mut memories = imports
+        .iter()
+        .filter_map(|import| import.ty.memory().cloned())
+        .collect::<Vec<_>>();
+    let mut tables = imports
+        .iter()
+        .filter_map(|import| import.ty.table().cloned())
+        .collect::<Vec<_>>();
+    let (custom_sections, function_names) = match sections {
+        Some(sections) => {
+            memories.extend(sections.memories.iter().cloned());
+            tables.extend(sections.tables.iter().cloned());
+            (
+                sections.custom_sections.clone(),
+                sections.function_names.clone(),
+            )
+        }
+        None => Default::default(),
+    };
+    ModuleInfo {
+        name: module.name().map(String::from),
+        imports,
+        exports,
+        memories,
+        tables,
+        custom_sections,
+        function_names,
+    }
+}
+
+fn memory_type(memory: wasmparser::MemoryType) -> Option<MemoryType> {
+    if memory.memory64 {
+        return Some(MemoryType::new64(memory.initial, memory.maximum));
+    }
+    let initial = u32::try_from(memory.initial).ok()?;
+    match (memory.shared, memory.maximum) {
+        (true, Some(maximum)) => Some(MemoryType::shared(initial, u32::try_from(maximum).ok()?)),
+        (true, None) => None,
+        (false, maximum) => Some(MemoryType::new(
+            initial,
+            maximum.map(u32::try_from).transpose().ok()?,
+        )),
+    }
+}
+
+fn table_type(table: wasmparser::TableType) -> Option<TableType> {
+    let element = match table.element_type {
+        wasmparser::RefType::FUNCREF => RefType::FUNCREF,
+        wasmparser::RefType::EXTERNREF => RefType::EXTERNREF,
+        _ => return None,
+    };
+    if table.table64 {
+        return Some(TableType::new64(element, table.initial, table.maximum));
+    }
+    Some(TableType::new(
+        element,
+        u32::try_from(table.initial).ok()?,
+        table.maximum.map(u32::try_from).transpose().ok()?,
+    ))
+}
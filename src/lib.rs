@@ -0,0 +1,1871 @@
+//! A runtime running WebAssembly modules as lightweight processes on top of
+//! tokio and wasmtime.
+//!
+//! Modules are compiled once with `Lunatic::load` and then started any number
+//! of times with `Lunatic::start`. Each process gets its own `Store` and a
+//! mailbox, is preempted through fuel metering or wasmtime's epochs and can
+//! be awaited, killed, linked, monitored and supervised.
+//!
+//! ```no_run
+//! # async fn example(wasm: &[u8]) -> anyhow::Result<()> {
+//! use lunatic::{Lunatic, Val};
+//!
+//! let (lunatic, runner) = Lunatic::new()?;
+//! tokio::spawn(runner);
+//!
+//! let module = lunatic.load(wasm)?;
+//! let _results = lunatic.start(module, "hello", &[Val::I64(3)])?.await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod builder;
+mod cache;
+mod error;
+pub mod host;
+mod info;
+mod limits;
+mod mailbox;
+mod repository;
+mod runner;
+mod supervisor;
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs,
+    future::Future,
+    mem, panic,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, Notify, Semaphore,
+    },
+    task::JoinHandle,
+};
+
+use anyhow::anyhow;
+use wasmtime::*;
+
+use cache::ModuleCache;
+use info::Sections;
+use limits::Limiter;
+use repository::Repository;
+
+pub use builder::{LunaticBuilder, Preemption};
+pub use error::{LunaticError, Result};
+pub use host::HostApi;
+pub use info::{CustomSection, ExportInfo, ImportInfo, ModuleInfo};
+pub use limits::ProcessLimits;
+pub use mailbox::{Mailbox, Message, DATA_MESSAGE, DOWN_MESSAGE, UPGRADE_MESSAGE};
+use runner::Stop;
+pub use runner::{Admission, QueueMetrics, ShutdownHandle, ShutdownSummary};
+pub use supervisor::{ChildSpec, Restart, Strategy, Supervisor};
+pub use wasmtime::{
+    Caller, ExternType, FuncType, Linker, Memory, MemoryType, OptLevel, TableType, Trap, Val,
+    ValType,
+};
+
+/// Identifies a module loaded into a `Lunatic` runtime.
+pub type ModuleId = u64;
+/// Identifies a process, unique for the lifetime of a `Lunatic` runtime.
+pub type ProcessId = u64;
+
+/// Id under which the embedding application sends and receives messages.
+pub const HOST: ProcessId = 0;
+
+/// Fuel a process gets before yielding back to the scheduler, if not configured.
+const DEFAULT_FUEL_SLICE: u64 = 1000;
+
+/// Entry point of WASI commands, called without arguments by `Lunatic::start_command`.
+const COMMAND_ENTRY: &str = "_start";
+
+struct LunaticInner {
+    next_module_id: AtomicU64,
+    next_process_id: AtomicU64,
+    modules: DashMap<u64, Module>,
+    // Process settings given when the module was loaded.
+    module_configs: DashMap<u64, ProcessConfig>,
+    processes: DashMap<u64, Process>,
+    // Ended processes from oldest to latest, their records are dropped once
+    // there are more than `history_len`.
+    history: Mutex<VecDeque<ProcessId>>,
+    history_len: usize,
+    mailboxes: DashMap<u64, Arc<Mailbox>>,
+    // Well-known names of processes, removed when the process ends.
+    names: DashMap<String, ProcessId>,
+    // Shared, so instantiating doesn't hold a lock of the map while the
+    // module's start function runs.
+    instance_pre: DashMap<u64, Arc<InstancePre<ProcessState>>>,
+    // Read from the module's bytes, missing for precompiled modules.
+    module_sections: DashMap<u64, Sections>,
+    // Modules loaded under a name and version.
+    repository: Repository,
+    // Present for every loaded module, removed first when it gets unloaded.
+    module_usage: DashMap<u64, ModuleUsage>,
+    default_config: RwLock<ProcessConfig>,
+    engine: Engine,
+    preemption: Preemption,
+    linker: Linker<ProcessState>,
+    sender: mpsc::Sender<QueuedProcess>,
+    queue_capacity: usize,
+    admission: Admission,
+    // Limits the processes running at once, `None` if they are not limited.
+    running_slots: Option<Arc<Semaphore>>,
+    max_running: Option<usize>,
+    running: AtomicUsize,
+    // Processes turned away because the queue was full.
+    rejected: AtomicU64,
+    // Creates the embedder's extension of every `ProcessState`.
+    extension: Option<ExtensionFn>,
+    module_cache: Option<ModuleCache>,
+    // Cleared once the runner was asked to stop.
+    accepting: AtomicBool,
+    // Taken by whoever asks the runner to stop.
+    shutdown: Mutex<Option<oneshot::Sender<Stop>>>,
+    // Number of `Lunatic` and `ShutdownHandle` values, the runner stops
+    // once they are all dropped.
+    handles: AtomicUsize,
+    // Notified whenever a process ends.
+    ended: Notify,
+}
+
+type ExtensionFn = Box<dyn Fn(ProcessId, ModuleId) -> Box<dyn Any + Send> + Send + Sync>;
+
+/// Data of a process' `Store`, available to host functions through the `Caller`.
+pub struct ProcessState {
+    id: ProcessId,
+    module_id: ModuleId,
+    runtime: Arc<LunaticInner>,
+    mailbox: Arc<Mailbox>,
+    // Kind and sender of the last message copied to the guest by `receive`.
+    last_kind: u32,
+    last_sender: ProcessId,
+    limiter: Limiter,
+    environment: HashMap<String, String>,
+    extension: Option<Box<dyn Any + Send>>,
+}
+
+impl ProcessState {
+    fn new(
+        id: ProcessId,
+        module_id: ModuleId,
+        runtime: Arc<LunaticInner>,
+        mailbox: Arc<Mailbox>,
+        config: ProcessConfig,
+    ) -> Self {
+        let extension = runtime
+            .extension
+            .as_ref()
+            .map(|extension| extension(id, module_id));
+        Self {
+            id,
+            module_id,
+            runtime,
+            mailbox,
+            last_kind: mailbox::DATA_MESSAGE,
+            last_sender: HOST,
+            limiter: Limiter::new(config.limits.unwrap_or_default()),
+            environment: config.environment.unwrap_or_default(),
+            extension,
+        }
+    }
+
+    /// Id of the process the store belongs to.
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    /// Module the process was started from.
+    pub fn module_id(&self) -> ModuleId {
+        self.module_id
+    }
+
+    /// The process' own mailbox, messages to other processes go through `send`.
+    pub fn mailbox(&self) -> &Mailbox {
+        &self.mailbox
+    }
+
+    /// Sends `data` to another process or the host, returns `false` if the
+    /// receiving process doesn't exist or has ended.
+    pub fn send(&self, to: ProcessId, data: Vec<u8>) -> bool {
+        let message = Message::Data {
+            from: self.id,
+            data,
+        };
+        self.runtime.deliver(to, message)
+    }
+
+    pub fn limits(&self) -> ProcessLimits {
+        self.limiter.limits()
+    }
+
+    /// Environment variables given through `ProcessConfig::environment`.
+    pub fn environment(&self) -> &HashMap<String, String> {
+        &self.environment
+    }
+
+    /// The embedder's extension, if it's of type `T`.
+    ///
+    /// It's created for every process by the function given to
+    /// `LunaticBuilder::extension`, or set later with `set_extension`.
+    pub fn extension<T: Any>(&self) -> Option<&T> {
+        self.extension.as_ref()?.downcast_ref()
+    }
+
+    pub fn extension_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.extension.as_mut()?.downcast_mut()
+    }
+
+    pub fn set_extension<T: Any + Send>(&mut self, extension: T) {
+        self.extension = Some(Box::new(extension));
+    }
+}
+
+/// Settings for processes, given per module to `Lunatic::load_with` or per
+/// process to `Lunatic::start_with`.
+///
+/// Unset fields fall back to the settings of the module, then to the runtime's.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessConfig {
+    pub limits: Option<ProcessLimits>,
+    /// Total fuel the process may consume before it ends with
+    /// `ProcessStatus::OutOfFuel`, unlimited by default.
+    pub fuel: Option<u64>,
+    /// Fuel consumed before the process yields to let others run.
+    pub fuel_slice: Option<u64>,
+    /// Environment variables, available to host functions through `ProcessState`.
+    pub environment: Option<HashMap<String, String>>,
+}
+
+impl ProcessConfig {
+    /// Fills the unset fields from `fallback`.
+    fn or(&self, fallback: &ProcessConfig) -> ProcessConfig {
+        ProcessConfig {
+            limits: match (&self.limits, &fallback.limits) {
+                (Some(limits), Some(fallback)) => Some(limits.or(fallback)),
+                (limits, fallback) => limits.or(*fallback),
+            },
+            fuel: self.fuel.or(fallback.fuel),
+            fuel_slice: self.fuel_slice.or(fallback.fuel_slice),
+            environment: self
+                .environment
+                .clone()
+                .or_else(|| fallback.environment.clone()),
+        }
+    }
+}
+
+/// Counts the processes of a module that haven't ended yet.
+struct ModuleUsage {
+    // Bumped by every upgrade, starting at 1 for the code given to `load`.
+    version: u32,
+    processes: usize,
+    // Set by `unload_when_idle`, no more processes can be started.
+    unloading: bool,
+}
+
+/// Bookkeeping the runtime keeps for every process, also for a while after
+/// it ended, see `LunaticBuilder::history`.
+struct Process {
+    module_id: ModuleId,
+    status: ProcessStatus,
+    started_at: Option<Instant>,
+    ended_at: Option<Instant>,
+    // Task driving the process, set once the runner picks it up.
+    task: Option<JoinHandle<()>>,
+    // Taken when the process ends, to resolve its `ProcessHandle`.
+    result: Option<oneshot::Sender<ProcessStatus>>,
+    // Processes killed if this one fails.
+    links: HashSet<ProcessId>,
+    // Processes (or the host) getting a `Message::Down` when this one ends.
+    monitors: HashSet<ProcessId>,
+    // Names the process is registered under, removed from the registry when it ends.
+    names: Vec<String>,
+}
+
+/// A process waiting in the runner queue.
+struct QueuedProcess {
+    module_id: ModuleId,
+    process_id: ProcessId,
+    function: String,
+    params: Vec<Val>,
+    mailbox: Arc<Mailbox>,
+    // Settings already merged with the module's and the runtime's.
+    config: ProcessConfig,
+}
+
+/// Lifecycle state of a process, after it ended the reason it did.
+#[derive(Debug, Clone)]
+pub enum ProcessStatus {
+    /// Queued, but not picked up by the runner yet.
+    Pending,
+    Running,
+    Finished(Vec<Val>),
+    /// The process called WASI's `proc_exit`, or a host function returned
+    /// `host::Exit`, with the given exit code.
+    Exited(i32),
+    Trapped {
+        message: String,
+        backtrace: Vec<String>,
+    },
+    Killed,
+    FailedToInstantiate(String),
+    /// A memory, table or instance limit was hit.
+    LimitExceeded(String),
+    /// The process consumed its whole fuel budget.
+    OutOfFuel,
+    /// The spawn queue was full, the process never ran.
+    Rejected,
+    /// Reason in the `Message::Down` for a monitored process that doesn't
+    /// exist, or ended too long ago to still be in the history.
+    NoProc,
+}
+
+impl ProcessStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, ProcessStatus::Pending | ProcessStatus::Running)
+    }
+
+    /// Whether the process ended abnormally, taking linked processes down with it.
+    pub fn is_failure(&self) -> bool {
+        self.is_finished() && !matches!(self, ProcessStatus::Finished(_) | ProcessStatus::Exited(0))
+    }
+
+    /// Turns the error a process' entry point failed with into its status.
+    fn from_error(error: anyhow::Error) -> Self {
+        if let Some(exit) = error.downcast_ref::<host::Exit>() {
+            return ProcessStatus::Exited(exit.0);
+        }
+        if let Some(Trap::OutOfFuel) = error.downcast_ref::<Trap>() {
+            return ProcessStatus::OutOfFuel;
+        }
+        let backtrace = error.downcast_ref::<WasmBacktrace>();
+        // The backtrace is attached as the outermost context, keep only the
+        // reason in the message.
+        let message = error
+            .chain()
+            .skip(backtrace.is_some() as usize)
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(": ");
+        let backtrace = match backtrace {
+            Some(backtrace) => backtrace
+                .frames()
+                .iter()
+                .enumerate()
+                .map(|(i, frame)| {
+                    format!(
+                        "{}: {}!{}",
+                        i,
+                        frame.module().name().unwrap_or("<unknown>"),
+                        frame
+                            .func_name()
+                            .map(String::from)
+                            .unwrap_or_else(|| format!("<wasm function {}>", frame.func_index()))
+                    )
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        ProcessStatus::Trapped { message, backtrace }
+    }
+
+    /// Status of a process whose task panicked, in a host function or the
+    /// embedder's extension function.
+    fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "unknown reason".to_string(),
+            },
+        };
+        ProcessStatus::Trapped {
+            message: format!("panicked: {}", message),
+            backtrace: Vec::new(),
+        }
+    }
+
+    /// Turns the final status into the result a `ProcessHandle` resolves to.
+    fn into_result(self) -> Result<Vec<Val>> {
+        match self {
+            ProcessStatus::Finished(results) => Ok(results),
+            ProcessStatus::Exited(0) => Ok(Vec::new()),
+            status => Err(LunaticError::from_status(status)),
+        }
+    }
+}
+
+impl fmt::Display for ProcessStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessStatus::Pending => write!(f, "pending"),
+            ProcessStatus::Running => write!(f, "running"),
+            ProcessStatus::Finished(results) => write!(f, "finished with {:?}", results),
+            ProcessStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ProcessStatus::Trapped { message, backtrace } => {
+                write!(f, "trapped: {}", message)?;
+                for frame in backtrace {
+                    write!(f, "\n    {}", frame)?;
+                }
+                Ok(())
+            }
+            ProcessStatus::Killed => write!(f, "killed"),
+            ProcessStatus::FailedToInstantiate(reason) => {
+                write!(f, "failed to instantiate: {}", reason)
+            }
+            ProcessStatus::LimitExceeded(reason) => write!(f, "limit exceeded: {}", reason),
+            ProcessStatus::OutOfFuel => write!(f, "out of fuel"),
+            ProcessStatus::Rejected => write!(f, "rejected, the spawn queue is full"),
+            ProcessStatus::NoProc => write!(f, "no such process"),
+        }
+    }
+}
+
+impl LunaticInner {
+    async fn run(self: Arc<Self>, process: QueuedProcess) -> ProcessStatus {
+        let state = ProcessState::new(
+            process.process_id,
+            process.module_id,
+            self.clone(),
+            process.mailbox,
+            process.config.clone(),
+        );
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limiter);
+        match self.preemption {
+            Preemption::Fuel => {
+                let fuel_slice = process
+                    .config
+                    .fuel_slice
+                    .unwrap_or(DEFAULT_FUEL_SLICE)
+                    .max(1);
+                store.set_fuel(process.config.fuel.unwrap_or(u64::MAX)).ok();
+                store.fuel_async_yield_interval(Some(fuel_slice)).ok();
+            }
+            Preemption::Epoch(_) => {
+                // Yields whenever the ticker advanced the epoch.
+                store.set_epoch_deadline(1);
+                store.epoch_deadline_async_yield_and_update(1);
+            }
+        }
+        let entry = match self
+            .instantiate(&mut store, process.module_id, &process.function)
+            .await
+        {
+            Ok(entry) => entry,
+            Err(error) => {
+                return match store.data().limiter.exceeded() {
+                    Some(reason) => ProcessStatus::LimitExceeded(reason.to_string()),
+                    None => ProcessStatus::FailedToInstantiate(error.to_string()),
+                }
+            }
+        };
+        let mut results = vec![Val::I32(0); entry.ty(&store).results().len()];
+        let status = match entry
+            .call_async(&mut store, &process.params, &mut results)
+            .await
+        {
+            Ok(()) => ProcessStatus::Finished(results),
+            Err(error) => ProcessStatus::from_error(error),
+        };
+        if let ProcessStatus::Trapped { .. } = status {
+            // A guest failing to grow its memory usually traps soon after, report the
+            // limit instead of whatever the guest did about it.
+            if let Some(reason) = store.data().limiter.exceeded() {
+                return ProcessStatus::LimitExceeded(reason.to_string());
+            }
+        }
+        status
+    }
+
+    async fn instantiate(
+        &self,
+        store: &mut Store<ProcessState>,
+        module_id: ModuleId,
+        function: &str,
+    ) -> anyhow::Result<Func> {
+        let instance_pre = self
+            .instance_pre
+            .get(&module_id)
+            .map(|instance_pre| instance_pre.clone())
+            .ok_or_else(|| anyhow!("module {} is not loaded", module_id))?;
+        let instance = instance_pre.instantiate_async(&mut *store).await?;
+        instance
+            .get_func(&mut *store, function)
+            .ok_or_else(|| anyhow!("module {} has no function export {}", module_id, function))
+    }
+
+    /// Checks that `function` is exported by the module and accepts `params`.
+    fn validate_entry(&self, module_id: ModuleId, function: &str, params: &[Val]) -> Result<()> {
+        let module = self
+            .modules
+            .get(&module_id)
+            .ok_or(LunaticError::UnknownModule(module_id))?;
+        let ty = match module.get_export(function) {
+            Some(ExternType::Func(ty)) => ty,
+            _ => {
+                return Err(LunaticError::MissingExport {
+                    module_id,
+                    function: function.to_string(),
+                })
+            }
+        };
+        let expected = ty.params().collect::<Vec<_>>();
+        let given = params.iter().map(val_type).collect::<Vec<_>>();
+        let matches = expected.len() == given.len()
+            && expected.iter().zip(&given).all(|(a, b)| ValType::eq(a, b));
+        if !matches {
+            return Err(LunaticError::SignatureMismatch {
+                function: function.to_string(),
+                expected,
+                given,
+            });
+        }
+        Ok(())
+    }
+
+    /// Validates the entry point and queues a new process for the runner.
+    ///
+    /// If the queue is full the process is turned away according to the
+    /// admission policy, without waiting for room.
+    fn start(
+        &self,
+        module_id: ModuleId,
+        function: &str,
+        params: &[Val],
+        config: &ProcessConfig,
+    ) -> Result<ProcessHandle> {
+        self.check_start(module_id, function, params)?;
+        match self.sender.try_reserve() {
+            Ok(permit) => self.queue(permit, module_id, function, params, config),
+            Err(TrySendError::Full(())) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                match self.admission {
+                    Admission::Reject => {
+                        let (id, handle, _) = self.create(module_id)?;
+                        self.finish(id, ProcessStatus::Rejected);
+                        Ok(handle)
+                    }
+                    Admission::Wait | Admission::TryFail => Err(LunaticError::QueueFull),
+                }
+            }
+            Err(TrySendError::Closed(())) => Err(LunaticError::RunnerStopped),
+        }
+    }
+
+    /// Like `start`, waiting for room in the queue if the admission policy is
+    /// `Admission::Wait`.
+    async fn start_async(
+        &self,
+        module_id: ModuleId,
+        function: &str,
+        params: &[Val],
+        config: &ProcessConfig,
+    ) -> Result<ProcessHandle> {
+        if self.admission != Admission::Wait {
+            return self.start(module_id, function, params, config);
+        }
+        self.check_start(module_id, function, params)?;
+        let permit = self
+            .sender
+            .reserve()
+            .await
+            .map_err(|_| LunaticError::RunnerStopped)?;
+        self.queue(permit, module_id, function, params, config)
+    }
+
+    fn check_start(&self, module_id: ModuleId, function: &str, params: &[Val]) -> Result<()> {
+        if !self.accepting.load(Ordering::SeqCst) {
+            return Err(LunaticError::ShuttingDown);
+        }
+        self.validate_entry(module_id, function, params)
+    }
+
+    /// Fails for settings the runtime's preemption can't honor.
+    fn check_config(&self, config: &ProcessConfig) -> Result<()> {
+        if let Preemption::Epoch(_) = self.preemption {
+            if config.fuel.is_some() || config.fuel_slice.is_some() {
+                return Err(LunaticError::Config(
+                    "fuel settings require Preemption::Fuel".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a process and sends it to the runner through the reserved `permit`.
+    fn queue(
+        &self,
+        permit: mpsc::Permit<'_, QueuedProcess>,
+        module_id: ModuleId,
+        function: &str,
+        params: &[Val],
+        config: &ProcessConfig,
+    ) -> Result<ProcessHandle> {
+        let default_config = self.default_config.read().unwrap();
+        let config = match self.module_configs.get(&module_id) {
+            Some(module_config) => config.or(&module_config).or(&default_config),
+            None => config.or(&default_config),
+        };
+        drop(default_config);
+        self.check_config(&config)?;
+        let (id, handle, mailbox) = self.create(module_id)?;
+        permit.send(QueuedProcess {
+            module_id,
+            process_id: id,
+            function: function.to_string(),
+            params: params.to_vec(),
+            mailbox,
+            config,
+        });
+        Ok(handle)
+    }
+
+    /// Registers a pending process and its mailbox.
+    ///
+    /// Fails if the module got unloaded since the entry point was validated.
+    fn create(&self, module_id: ModuleId) -> Result<(ProcessId, ProcessHandle, Arc<Mailbox>)> {
+        // Counted under the lock of the module's entry, so `unload` either sees
+        // the process or the process sees the module gone.
+        match self.module_usage.get_mut(&module_id) {
+            Some(mut usage) if !usage.unloading => usage.processes += 1,
+            Some(_) => return Err(LunaticError::ModuleUnloading(module_id)),
+            None => return Err(LunaticError::UnknownModule(module_id)),
+        }
+        let id = self.next_process_id.fetch_add(1, Ordering::Relaxed);
+        let (result_sender, result) = oneshot::channel();
+        let mailbox = Arc::new(Mailbox::default());
+        self.mailboxes.insert(id, mailbox.clone());
+        self.processes.insert(
+            id,
+            Process {
+                module_id,
+                status: ProcessStatus::Pending,
+                started_at: None,
+                ended_at: None,
+                task: None,
+                result: Some(result_sender),
+                links: HashSet::new(),
+                monitors: HashSet::new(),
+                names: Vec::new(),
+            },
+        );
+        Ok((id, ProcessHandle { id, result }, mailbox))
+    }
+
+    /// Unloads a module right away, if none of its processes is alive.
+    fn unload(&self, module_id: ModuleId) -> Result<()> {
+        match self.module_usage.entry(module_id) {
+            Entry::Occupied(entry) if entry.get().processes > 0 => {
+                return Err(LunaticError::ModuleInUse {
+                    module_id,
+                    processes: entry.get().processes,
+                })
+            }
+            Entry::Occupied(entry) => {
+                entry.remove();
+            }
+            Entry::Vacant(_) => return Err(LunaticError::UnknownModule(module_id)),
+        }
+        self.free_module(module_id);
+        Ok(())
+    }
+
+    /// Stops new processes of a module from starting and unloads it once the
+    /// last one ended.
+    ///
+    /// Returns `true` if the module was unloaded right away.
+    fn unload_when_idle(&self, module_id: ModuleId) -> Result<bool> {
+        let idle = match self.module_usage.get_mut(&module_id) {
+            Some(mut usage) => {
+                usage.unloading = true;
+                usage.processes == 0
+            }
+            None => return Err(LunaticError::UnknownModule(module_id)),
+        };
+        Ok(idle && self.remove_idle_module(module_id))
+    }
+
+    /// Drops the count of a process that ended, unloading its module if it
+    /// was waiting for that.
+    fn release_module(&self, module_id: ModuleId) {
+        let idle = match self.module_usage.get_mut(&module_id) {
+            Some(mut usage) => {
+                usage.processes -= 1;
+                usage.unloading && usage.processes == 0
+            }
+            None => false,
+        };
+        if idle {
+            self.remove_idle_module(module_id);
+        }
+    }
+
+    fn remove_idle_module(&self, module_id: ModuleId) -> bool {
+        let removed = self
+            .module_usage
+            .remove_if(&module_id, |_, usage| usage.processes == 0)
+            .is_some();
+        if removed {
+            self.free_module(module_id);
+        }
+        removed
+    }
+
+    /// Drops the compiled code of an unloaded module. Instances of it still
+    /// alive in a process' `Store` keep it around until the store is dropped.
+    fn free_module(&self, module_id: ModuleId) {
+        self.instance_pre.remove(&module_id);
+        self.modules.remove(&module_id);
+        self.module_configs.remove(&module_id);
+        self.module_sections.remove(&module_id);
+        self.repository.remove(module_id);
+    }
+
+    /// Puts a message into the mailbox of a process or the host.
+    ///
+    /// Returns `false` if there is no such mailbox, i.e. the process has ended.
+    fn deliver(&self, to: ProcessId, message: Message) -> bool {
+        match self.mailboxes.get(&to) {
+            Some(mailbox) => {
+                mailbox.push(message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves a pending process into the running state.
+    ///
+    /// Returns `false` if the process was killed while queued.
+    fn set_running(&self, process_id: ProcessId) -> bool {
+        match self.processes.get_mut(&process_id) {
+            Some(mut process) if matches!(process.status, ProcessStatus::Pending) => {
+                process.status = ProcessStatus::Running;
+                process.started_at = Some(Instant::now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the final status of a process and resolves its handle.
+    ///
+    /// Only the first call for a process has an effect, so a process that was
+    /// killed stays killed even if its task manages to finish afterwards.
+    /// Returns `false` if the process had already ended.
+    fn finish(&self, process_id: ProcessId, status: ProcessStatus) -> bool {
+        let (module_id, task, result, links, monitors, names) =
+            match self.processes.get_mut(&process_id) {
+                Some(mut process) if !process.status.is_finished() => {
+                    process.status = status.clone();
+                    process.ended_at = Some(Instant::now());
+                    (
+                        process.module_id,
+                        process.task.take(),
+                        process.result.take(),
+                        mem::take(&mut process.links),
+                        mem::take(&mut process.monitors),
+                        mem::take(&mut process.names),
+                    )
+                }
+                _ => return false,
+            };
+        // Messages still sent to the process are dropped from now on.
+        self.mailboxes.remove(&process_id);
+        self.forget_oldest(process_id);
+        for name in names {
+            self.names.remove_if(&name, |_, id| *id == process_id);
+        }
+        self.release_module(module_id);
+        self.ended.notify_one();
+        if let (ProcessStatus::Killed, Some(task)) = (&status, task) {
+            // Aborting drops the future and with it the process' `Store`, also if
+            // the process is currently suspended waiting for more fuel.
+            task.abort();
+        }
+        for monitor in monitors {
+            let down = Message::Down {
+                process_id,
+                reason: status.clone(),
+            };
+            self.deliver(monitor, down);
+        }
+        for linked in links {
+            if let Some(mut process) = self.processes.get_mut(&linked) {
+                process.links.remove(&process_id);
+            }
+            if status.is_failure() {
+                self.finish(linked, ProcessStatus::Killed);
+            }
+        }
+        if let Some(result) = result {
+            // The handle may have been dropped, nobody is interested in the result then.
+            result.send(status).ok();
+        }
+        true
+    }
+
+    /// Adds an ended process to the history, dropping the records of the
+    /// oldest ended processes beyond its length.
+    fn forget_oldest(&self, process_id: ProcessId) {
+        let mut history = self.history.lock().unwrap();
+        history.push_back(process_id);
+        while history.len() > self.history_len {
+            if let Some(oldest) = history.pop_front() {
+                self.processes.remove(&oldest);
+            }
+        }
+    }
+
+    /// Registers a running process under `name`.
+    fn register(&self, name: String, process_id: ProcessId) -> Result<()> {
+        match self.names.entry(name.clone()) {
+            Entry::Occupied(entry) => {
+                return Err(LunaticError::NameTaken {
+                    name: entry.key().clone(),
+                    process_id: *entry.get(),
+                })
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(process_id);
+            }
+        }
+        // Added after inserting, so either `finish` sees the name or we see it ended.
+        match self.processes.get_mut(&process_id) {
+            Some(mut process) if !process.status.is_finished() => {
+                process.names.push(name);
+                Ok(())
+            }
+            _ => {
+                self.names.remove_if(&name, |_, id| *id == process_id);
+                Err(LunaticError::UnknownProcess(process_id))
+            }
+        }
+    }
+
+    /// Removes `name` from the registry, returns the process it pointed to.
+    fn unregister(&self, name: &str) -> Option<ProcessId> {
+        let (name, process_id) = self.names.remove(name)?;
+        if let Some(mut process) = self.processes.get_mut(&process_id) {
+            process.names.retain(|registered| *registered != name);
+        }
+        Some(process_id)
+    }
+
+    /// Adds `to` to the links of `process_id` if the latter is still alive.
+    fn add_link(&self, process_id: ProcessId, to: ProcessId) -> bool {
+        match self.processes.get_mut(&process_id) {
+            Some(mut process) if !process.status.is_finished() => {
+                process.links.insert(to);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Links two processes, so that a failure of one kills the other.
+    ///
+    /// Returns `false` if either of them has already ended.
+    fn link(&self, a: ProcessId, b: ProcessId) -> bool {
+        // Both sides are updated separately, only ever holding one entry of the map.
+        if !self.add_link(a, b) {
+            return false;
+        }
+        if !self.add_link(b, a) {
+            self.unlink(a, b);
+            return false;
+        }
+        true
+    }
+
+    fn unlink(&self, a: ProcessId, b: ProcessId) {
+        if let Some(mut process) = self.processes.get_mut(&a) {
+            process.links.remove(&b);
+        }
+        if let Some(mut process) = self.processes.get_mut(&b) {
+            process.links.remove(&a);
+        }
+    }
+
+    /// Lets `watcher` receive a `Message::Down` once `process_id` ends.
+    ///
+    /// If the process has already ended, the message is delivered right away,
+    /// with `ProcessStatus::NoProc` if it's not known (anymore).
+    fn monitor(&self, watcher: ProcessId, process_id: ProcessId) {
+        let reason = match self.processes.get_mut(&process_id) {
+            Some(mut process) if !process.status.is_finished() => {
+                process.monitors.insert(watcher);
+                return;
+            }
+            Some(process) => process.status.clone(),
+            None => ProcessStatus::NoProc,
+        };
+        self.deliver(watcher, Message::Down { process_id, reason });
+    }
+
+    fn demonitor(&self, watcher: ProcessId, process_id: ProcessId) {
+        if let Some(mut process) = self.processes.get_mut(&process_id) {
+            process.monitors.remove(&watcher);
+        }
+    }
+}
+
+/// Type of a value given as a parameter, without the store that reference
+/// values belong to.
+fn val_type(val: &Val) -> ValType {
+    match val {
+        Val::I32(_) => ValType::I32,
+        Val::I64(_) => ValType::I64,
+        Val::F32(_) => ValType::F32,
+        Val::F64(_) => ValType::F64,
+        Val::V128(_) => ValType::V128,
+        Val::FuncRef(_) => ValType::FUNCREF,
+        Val::ExternRef(_) => ValType::EXTERNREF,
+        Val::AnyRef(_) => ValType::ANYREF,
+    }
+}
+
+/// Handle to the runtime, used to load modules and to start and manage processes.
+///
+/// Processes only make progress while the runner future returned alongside it
+/// is polled, usually by spawning it on the tokio runtime. The handle is cheap
+/// to clone and can be used from many tasks and threads at once. Once every
+/// handle (and `ShutdownHandle`) is dropped, the runner resolves after the
+/// processes started so far ended.
+pub struct Lunatic {
+    inner: Arc<LunaticInner>,
+}
+
+impl Clone for Lunatic {
+    fn clone(&self) -> Self {
+        runner::acquire(&self.inner);
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for Lunatic {
+    fn drop(&mut self) {
+        runner::release(&self.inner);
+    }
+}
+
+/// Handle to a started process.
+///
+/// Awaiting it resolves to the value returned by the process, or to the
+/// `LunaticError` (instantiation failure, trap, ...) that ended it.
+pub struct ProcessHandle {
+    id: ProcessId,
+    result: oneshot::Receiver<ProcessStatus>,
+}
+
+impl ProcessHandle {
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    /// Waits for the process to end, resolving to its final status.
+    pub(crate) async fn status(self) -> ProcessStatus {
+        self.result.await.unwrap_or(ProcessStatus::Killed)
+    }
+}
+
+impl Future for ProcessHandle {
+    type Output = Result<Vec<Val>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The sender only gets dropped without a result if the runtime itself is gone.
+        Pin::new(&mut self.result)
+            .poll(cx)
+            .map(|status| status.map_or(Err(LunaticError::Killed), ProcessStatus::into_result))
+    }
+}
+
+impl Lunatic {
+    /// Creates a runtime with the default configuration, see `LunaticBuilder`.
+    pub fn new() -> Result<(Self, impl Future<Output = ShutdownSummary>)> {
+        LunaticBuilder::new().build()
+    }
+
+    /// Returns a handle to shut the runtime down, it can be moved elsewhere.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.inner.clone())
+    }
+
+    /// Returns a builder to configure the runtime before creating it.
+    pub fn builder() -> LunaticBuilder {
+        LunaticBuilder::new()
+    }
+
+    /// Starts a process calling the exported `function` with `params`.
+    ///
+    /// The export and its signature are checked before the process is queued.
+    /// If the spawn queue is full this fails, or with `Admission::Reject`
+    /// returns a process that ended as `ProcessStatus::Rejected`.
+    pub fn start(
+        &self,
+        module_id: ModuleId,
+        function: &str,
+        params: &[Val],
+    ) -> Result<ProcessHandle> {
+        self.start_with(module_id, function, params, &ProcessConfig::default())
+    }
+
+    /// Like `start`, overriding the module's process settings with `config`.
+    ///
+    /// Fuel settings fail with `LunaticError::Config` under `Preemption::Epoch`,
+    /// also if they come from the module's or the default settings.
+    pub fn start_with(
+        &self,
+        module_id: ModuleId,
+        function: &str,
+        params: &[Val],
+        config: &ProcessConfig,
+    ) -> Result<ProcessHandle> {
+        self.inner.start(module_id, function, params, config)
+    }
+
+    /// Async variant of `start`, waiting for room in the spawn queue if the
+    /// runtime was built with `Admission::Wait`.
+    pub async fn start_async(
+        &self,
+        module_id: ModuleId,
+        function: &str,
+        params: &[Val],
+    ) -> Result<ProcessHandle> {
+        self.start_with_async(module_id, function, params, &ProcessConfig::default())
+            .await
+    }
+
+    /// Async variant of `start_with`.
+    pub async fn start_with_async(
+        &self,
+        module_id: ModuleId,
+        function: &str,
+        params: &[Val],
+        config: &ProcessConfig,
+    ) -> Result<ProcessHandle> {
+        self.inner
+            .start_async(module_id, function, params, config)
+            .await
+    }
+
+    /// Starts a process of the module `spec` refers to, either `name@version`
+    /// or just `name` for its latest version.
+    pub fn start_named(&self, spec: &str, function: &str, params: &[Val]) -> Result<ProcessHandle> {
+        self.start(self.resolve(spec)?, function, params)
+    }
+
+    /// Starts a command, a module exporting `_start` without parameters.
+    ///
+    /// Of WASI only `proc_exit` is provided, a command calling it finishes with
+    /// `ProcessStatus::Exited`. Commands importing other WASI functions fail to
+    /// load with `LunaticError::LinkError`.
+    pub fn start_command(&self, module_id: ModuleId) -> Result<ProcessHandle> {
+        self.start(module_id, COMMAND_ENTRY, &[])
+    }
+
+    /// Returns the current status of a process, `None` if the id was never
+    /// handed out or the process ended too long ago to still be in the history.
+    pub fn status(&self, process_id: ProcessId) -> Option<ProcessStatus> {
+        self.inner
+            .processes
+            .get(&process_id)
+            .map(|process| process.status.clone())
+    }
+
+    /// Returns when the runner started the process and when it ended, if it did.
+    pub fn timing(&self, process_id: ProcessId) -> Option<(Instant, Option<Instant>)> {
+        let process = self.inner.processes.get(&process_id)?;
+        Some((process.started_at?, process.ended_at))
+    }
+
+    /// Returns the current size of the spawn queue and number of running processes.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        let inner = &self.inner;
+        QueueMetrics {
+            queued: inner.queue_capacity - inner.sender.capacity(),
+            queue_capacity: inner.queue_capacity,
+            running: inner.running.load(Ordering::Relaxed),
+            max_running: inner.max_running,
+            rejected: inner.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Kills a queued or running process.
+    ///
+    /// Returns `false` if the process doesn't exist or has already ended.
+    pub fn kill(&self, process_id: ProcessId) -> bool {
+        self.inner.finish(process_id, ProcessStatus::Killed)
+    }
+
+    /// Sends `data` from the host to the mailbox of a process.
+    pub fn send(&self, process_id: ProcessId, data: impl Into<Vec<u8>>) -> Result<()> {
+        let message = Message::Data {
+            from: HOST,
+            data: data.into(),
+        };
+        if self.inner.deliver(process_id, message) {
+            Ok(())
+        } else {
+            Err(LunaticError::UnknownProcess(process_id))
+        }
+    }
+
+    /// Receives the next message processes sent to the host (`HOST`).
+    ///
+    /// Returns `None` if no message arrived within `timeout`.
+    pub async fn receive(&self, timeout: Option<Duration>) -> Option<Message> {
+        let mailbox = self.inner.mailboxes.get(&HOST)?.clone();
+        mailbox.pop(timeout).await
+    }
+
+    /// Links two processes, if one of them fails the other one gets killed.
+    ///
+    /// Returns `false` if either of them has already ended.
+    pub fn link(&self, a: ProcessId, b: ProcessId) -> bool {
+        self.inner.link(a, b)
+    }
+
+    /// Removes a link created with `link`.
+    pub fn unlink(&self, a: ProcessId, b: ProcessId) {
+        self.inner.unlink(a, b)
+    }
+
+    /// Lets the host receive a `Message::Down` once the process ends.
+    pub fn monitor(&self, process_id: ProcessId) {
+        self.inner.monitor(HOST, process_id)
+    }
+
+    /// Stops monitoring a process, a `Message::Down` may already be in the mailbox.
+    pub fn demonitor(&self, process_id: ProcessId) {
+        self.inner.demonitor(HOST, process_id)
+    }
+
+    /// Registers a process under `name`, until it ends or gets unregistered.
+    pub fn register(&self, name: &str, process_id: ProcessId) -> Result<()> {
+        self.inner.register(name.to_string(), process_id)
+    }
+
+    /// Returns the process registered under `name`.
+    pub fn whereis(&self, name: &str) -> Option<ProcessId> {
+        self.inner.names.get(name).map(|process_id| *process_id)
+    }
+
+    /// Removes `name` from the registry, returns the process it pointed to.
+    pub fn unregister(&self, name: &str) -> Option<ProcessId> {
+        self.inner.unregister(name)
+    }
+
+    /// Sets the process settings used where neither the module nor `start_with`
+    /// configure them.
+    pub fn set_default_config(&self, config: ProcessConfig) {
+        *self.inner.default_config.write().unwrap() = config;
+    }
+
+    /// Compiles a module and prepares it for instantiation.
+    ///
+    /// All imports have to be satisfied by the host functions of the runtime.
+    pub fn load(&self, bytes: impl AsRef<[u8]>) -> Result<ModuleId> {
+        self.load_with(bytes, ProcessConfig::default())
+    }
+
+    /// Like `load`, with `config` as the settings for processes of this module.
+    ///
+    /// Fuel settings fail with `LunaticError::Config` under `Preemption::Epoch`.
+    pub fn load_with(&self, bytes: impl AsRef<[u8]>, config: ProcessConfig) -> Result<ModuleId> {
+        self.inner.check_config(&config)?;
+        let module = self.compile(bytes.as_ref())?;
+        let sections = Sections::parse(bytes.as_ref());
+        self.insert_module(module, config, Some(sections))
+    }
+
+    /// Like `load`, making the module available as `name@version` to
+    /// `start_named` and `resolve`.
+    pub fn load_named(
+        &self,
+        name: &str,
+        version: &str,
+        bytes: impl AsRef<[u8]>,
+    ) -> Result<ModuleId> {
+        let spec = format!("{}@{}", name, version);
+        if !repository::is_valid(name, version) {
+            return Err(LunaticError::InvalidModuleName(spec));
+        }
+        if self.inner.repository.contains(name, version) {
+            return Err(LunaticError::ModuleNameTaken(spec));
+        }
+        let module_id = self.load(bytes)?;
+        // Someone else may have loaded the same name in the meantime.
+        if !self.inner.repository.insert(name, version, module_id) {
+            self.inner.unload(module_id).ok();
+            return Err(LunaticError::ModuleNameTaken(spec));
+        }
+        Ok(module_id)
+    }
+
+    /// Loads the module file `name@version.wasm` (or `.wat`) as `name@version`,
+    /// files without a version in their name get version `0`.
+    pub fn load_file(&self, path: impl AsRef<Path>) -> Result<ModuleId> {
+        let path = path.as_ref();
+        let (name, version) = repository::file_spec(path)
+            .ok_or_else(|| LunaticError::InvalidModuleName(path.display().to_string()))?;
+        let bytes = fs::read(path)
+            .map_err(|error| LunaticError::Io(format!("{}: {}", path.display(), error)))?;
+        self.load_named(&name, &version, bytes)
+    }
+
+    /// Loads all `.wasm` and `.wat` files of a directory with `load_file`, in
+    /// the order of their names.
+    ///
+    /// Stops at the first file failing to load, the ones before stay loaded.
+    pub fn load_dir(&self, path: impl AsRef<Path>) -> Result<Vec<ModuleId>> {
+        let path = path.as_ref();
+        let io_error =
+            |error: std::io::Error| LunaticError::Io(format!("{}: {}", path.display(), error));
+        let mut files = fs::read_dir(path)
+            .map_err(io_error)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(io_error)?;
+        files.retain(|file| file.is_file() && repository::file_spec(file).is_some());
+        files.sort();
+        files.iter().map(|file| self.load_file(file)).collect()
+    }
+
+    /// Returns the module `spec` refers to, either `name@version` or just
+    /// `name` for its latest version.
+    ///
+    /// Versions are compared by their dot separated components, numerically
+    /// where possible.
+    pub fn resolve(&self, spec: &str) -> Result<ModuleId> {
+        self.inner
+            .repository
+            .resolve(spec)
+            .ok_or_else(|| LunaticError::UnknownModuleName(spec.to_string()))
+    }
+
+    /// Returns the loaded versions of `name` with their modules, from oldest to latest.
+    pub fn versions(&self, name: &str) -> Vec<(String, ModuleId)> {
+        self.inner.repository.versions(name)
+    }
+
+    /// Compiles a module into an artifact that `load_precompiled` can load
+    /// without compiling it again, in this or another runtime with the same
+    /// engine configuration.
+    pub fn precompile(&self, bytes: impl AsRef<[u8]>) -> Result<Vec<u8>> {
+        self.inner
+            .engine
+            .precompile_module(bytes.as_ref())
+            .map_err(|error| LunaticError::CompileError(error.to_string()))
+    }
+
+    /// Loads an artifact created by `precompile` or `Module::serialize`.
+    ///
+    /// Artifacts of another wasmtime version or engine configuration are refused.
+    ///
+    /// # Safety
+    ///
+    /// The artifact's machine code is run as is, it must come from a trusted source.
+    pub unsafe fn load_precompiled(
+        &self,
+        artifact: impl AsRef<[u8]>,
+        config: ProcessConfig,
+    ) -> Result<ModuleId> {
+        self.inner.check_config(&config)?;
+        let module = Module::deserialize(&self.inner.engine, artifact)
+            .map_err(|error| LunaticError::CompileError(error.to_string()))?;
+        self.insert_module(module, config, None)
+    }
+
+    /// Removes the artifacts the module cache holds for the current engine
+    /// configuration, modules are compiled again when loaded the next time.
+    pub fn clear_module_cache(&self) -> std::io::Result<()> {
+        match &self.inner.module_cache {
+            Some(cache) => cache.clear(),
+            None => Ok(()),
+        }
+    }
+
+    fn insert_module(
+        &self,
+        module: Module,
+        config: ProcessConfig,
+        sections: Option<Sections>,
+    ) -> Result<ModuleId> {
+        let id = self.inner.next_module_id.fetch_add(1, Ordering::Relaxed);
+        let instance_pre = self.prepare(&module)?;
+        if let Some(sections) = sections {
+            self.inner.module_sections.insert(id, sections);
+        }
+        self.inner.modules.insert(id, module);
+        self.inner.module_configs.insert(id, config);
+        self.inner.instance_pre.insert(id, instance_pre);
+        let usage = ModuleUsage {
+            version: 1,
+            processes: 0,
+            unloading: false,
+        };
+        self.inner.module_usage.insert(id, usage);
+        Ok(id)
+    }
+
+    /// Replaces the code of a loaded module, returning its new version.
+    ///
+    /// Processes started from now on run the new code, including ones still
+    /// queued. Running processes keep the code they were instantiated with
+    /// and, if `notify` is set, get a `Message::Upgrade` to migrate their state.
+    pub fn upgrade(
+        &self,
+        module_id: ModuleId,
+        bytes: impl AsRef<[u8]>,
+        notify: bool,
+    ) -> Result<u32> {
+        let module = self.compile(bytes.as_ref())?;
+        let instance_pre = self.prepare(&module)?;
+        let sections = Sections::parse(bytes.as_ref());
+        let version = match self.inner.module_usage.get_mut(&module_id) {
+            Some(mut usage) if !usage.unloading => {
+                // Swapped under the lock processes are counted with, a starting
+                // process sees either the old or the new version.
+                self.inner.modules.insert(module_id, module);
+                self.inner.instance_pre.insert(module_id, instance_pre);
+                self.inner.module_sections.insert(module_id, sections);
+                usage.version += 1;
+                usage.version
+            }
+            Some(_) => return Err(LunaticError::ModuleUnloading(module_id)),
+            None => return Err(LunaticError::UnknownModule(module_id)),
+        };
+        if notify {
+            let running = self
+                .inner
+                .processes
+                .iter()
+                .filter(|process| {
+                    process.module_id == module_id
+                        && matches!(process.status, ProcessStatus::Running)
+                })
+                .map(|process| *process.key())
+                .collect::<Vec<_>>();
+            for process_id in running {
+                let message = Message::Upgrade { module_id, version };
+                self.inner.deliver(process_id, message);
+            }
+        }
+        Ok(version)
+    }
+
+    /// Describes the current version of a module, `None` if it isn't loaded.
+    ///
+    /// Modules loaded with `load_precompiled` only list their imported memories
+    /// and tables and have no custom sections or function names.
+    pub fn module_info(&self, module_id: ModuleId) -> Option<ModuleInfo> {
+        let module = self.inner.modules.get(&module_id)?;
+        let sections = self.inner.module_sections.get(&module_id);
+        Some(info::module_info(&module, sections.as_deref()))
+    }
+
+    /// Returns the current version of a module, `None` if it isn't loaded.
+    pub fn module_version(&self, module_id: ModuleId) -> Option<u32> {
+        self.inner
+            .module_usage
+            .get(&module_id)
+            .map(|usage| usage.version)
+    }
+
+    /// Compiles a module, going through the module cache if there is one.
+    fn compile(&self, bytes: &[u8]) -> Result<Module> {
+        match &self.inner.module_cache {
+            Some(cache) => cache.load(&self.inner.engine, bytes),
+            None => Module::new(&self.inner.engine, bytes),
+        }
+        .map_err(|error| LunaticError::CompileError(error.to_string()))
+    }
+
+    /// Checks the imports of a module against the host functions.
+    fn prepare(&self, module: &Module) -> Result<Arc<InstancePre<ProcessState>>> {
+        self.inner
+            .linker
+            .instantiate_pre(module)
+            .map(Arc::new)
+            .map_err(|error| LunaticError::LinkError(error.to_string()))
+    }
+
+    /// Unloads a module, freeing its compiled code.
+    ///
+    /// Fails with `LunaticError::ModuleInUse` while processes of the module
+    /// are queued or running, see `unload_when_idle` to wait for them instead.
+    pub fn unload(&self, module_id: ModuleId) -> Result<()> {
+        self.inner.unload(module_id)
+    }
+
+    /// Unloads a module once its last process ended, new processes of it
+    /// can't be started from now on.
+    ///
+    /// Returns `true` if no process was alive and the module got unloaded right away.
+    pub fn unload_when_idle(&self, module_id: ModuleId) -> Result<bool> {
+        self.inner.unload_when_idle(module_id)
+    }
+
+    /// Returns the number of queued and running processes of a module, `None`
+    /// if it isn't loaded.
+    pub fn module_processes(&self, module_id: ModuleId) -> Option<usize> {
+        self.inner
+            .module_usage
+            .get(&module_id)
+            .map(|usage| usage.processes)
+    }
+
+    /// Like `load`, compiling on tokio's blocking thread pool instead of the
+    /// calling task.
+    pub async fn load_async(&self, bytes: impl Into<Vec<u8>>) -> Result<ModuleId> {
+        self.load_with_async(bytes, ProcessConfig::default()).await
+    }
+
+    /// Like `load_with`, compiling on tokio's blocking thread pool.
+    pub async fn load_with_async(
+        &self,
+        bytes: impl Into<Vec<u8>>,
+        config: ProcessConfig,
+    ) -> Result<ModuleId> {
+        let lunatic = self.clone();
+        let bytes = bytes.into();
+        match tokio::task::spawn_blocking(move || lunatic.load_with(bytes, config)).await {
+            Ok(result) => result,
+            // A panic while compiling is passed on to the caller, as with `load_with`.
+            Err(error) if error.is_panic() => panic::resume_unwind(error.into_panic()),
+            // Blocking tasks are only cancelled when tokio's runtime shuts down.
+            Err(_) => Err(LunaticError::ShuttingDown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST: &str = r#"
+        (module
+            (import "host" "receive" (func $receive (param i32 i32 i64) (result i64)))
+            (memory (export "memory") 1)
+            (func (export "nop"))
+            (func (export "spin") (loop br 0))
+            (func (export "fail") unreachable)
+            (func (export "wait")
+                (drop (call $receive (i32.const 0) (i32.const 64) (i64.const -1))))
+            (func (export "poll") (result i64)
+                (call $receive (i32.const 0) (i32.const 64) (i64.const 0))))
+    "#;
+
+    fn runtime() -> (Lunatic, ModuleId) {
+        let (lunatic, runner) = Lunatic::new().unwrap();
+        tokio::spawn(runner);
+        let module = lunatic.load(GUEST).unwrap();
+        (lunatic, module)
+    }
+
+    async fn wait_until_running(lunatic: &Lunatic, process_id: ProcessId) {
+        while matches!(lunatic.status(process_id), Some(ProcessStatus::Pending)) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn failure_kills_linked_processes() {
+        let (lunatic, module) = runtime();
+        let a = lunatic.start(module, "spin", &[]).unwrap();
+        let b = lunatic.start(module, "spin", &[]).unwrap();
+        assert!(lunatic.link(a.id(), b.id()));
+
+        assert!(lunatic.kill(b.id()));
+        assert!(matches!(a.await, Err(LunaticError::Killed)));
+    }
+
+    #[tokio::test]
+    async fn normal_exit_leaves_linked_processes_running() {
+        let (lunatic, module) = runtime();
+        let a = lunatic.start(module, "spin", &[]).unwrap();
+        let b = lunatic.start(module, "wait", &[]).unwrap();
+        assert!(lunatic.link(a.id(), b.id()));
+
+        lunatic.send(b.id(), "done").unwrap();
+        b.await.unwrap();
+        assert!(!lunatic.status(a.id()).unwrap().is_finished());
+        assert!(lunatic.kill(a.id()));
+    }
+
+    #[tokio::test]
+    async fn link_to_ended_process_fails() {
+        let (lunatic, module) = runtime();
+        let a = lunatic.start(module, "spin", &[]).unwrap();
+        let b = lunatic.start(module, "fail", &[]).unwrap();
+        let b_id = b.id();
+        assert!(matches!(b.await, Err(LunaticError::Trap { .. })));
+
+        assert!(!lunatic.link(a.id(), b_id));
+        assert!(!lunatic.status(a.id()).unwrap().is_finished());
+        lunatic.kill(a.id());
+    }
+
+    #[tokio::test]
+    async fn monitor_receives_down_message() {
+        let (lunatic, module) = runtime();
+        let process = lunatic.start(module, "fail", &[]).unwrap();
+        let process_id = process.id();
+        lunatic.monitor(process_id);
+
+        match lunatic.receive(Some(Duration::from_secs(5))).await {
+            Some(Message::Down {
+                process_id: id,
+                reason,
+            }) => {
+                assert_eq!(id, process_id);
+                assert!(matches!(reason, ProcessStatus::Trapped { .. }));
+            }
+            message => panic!("expected a down message, got {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn monitor_of_unknown_process_is_noproc() {
+        let (lunatic, _) = runtime();
+        lunatic.monitor(1000);
+
+        match lunatic.receive(Some(Duration::from_secs(5))).await {
+            Some(Message::Down {
+                process_id: 1000,
+                reason: ProcessStatus::NoProc,
+            }) => (),
+            message => panic!("expected a noproc down message, got {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn demonitor_stops_down_messages() {
+        let (lunatic, module) = runtime();
+        let process = lunatic.start(module, "spin", &[]).unwrap();
+        wait_until_running(&lunatic, process.id()).await;
+        lunatic.monitor(process.id());
+        lunatic.demonitor(process.id());
+
+        lunatic.kill(process.id());
+        assert!(lunatic
+            .receive(Some(Duration::from_millis(50)))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn command_exits_through_proc_exit() {
+        let (lunatic, _) = runtime();
+        let command = |code| {
+            format!(
+                r#"(module
+                    (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+                    (func (export "_start") i32.const {} call $exit unreachable))"#,
+                code
+            )
+        };
+        let module = lunatic.load(command(3)).unwrap();
+        let process = lunatic.start_command(module).unwrap();
+        let process_id = process.id();
+        assert!(matches!(process.await, Err(LunaticError::Exited(3))));
+        assert!(matches!(
+            lunatic.status(process_id),
+            Some(ProcessStatus::Exited(3))
+        ));
+
+        let module = lunatic.load(command(0)).unwrap();
+        assert!(lunatic.start_command(module).unwrap().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn receive_with_zero_timeout_polls() {
+        let (lunatic, runner) = Lunatic::new().unwrap();
+        let module = lunatic.load(GUEST).unwrap();
+        let empty = lunatic.start(module, "poll", &[]).unwrap();
+        let full = lunatic.start(module, "poll", &[]).unwrap();
+        lunatic.send(full.id(), "hello").unwrap();
+        tokio::spawn(runner);
+
+        let results = tokio::time::timeout(Duration::from_secs(5), empty)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(results[..], [Val::I64(-1)]));
+        assert!(matches!(full.await.unwrap()[..], [Val::I64(5)]));
+    }
+
+    #[tokio::test]
+    async fn fuel_budget_ends_process() {
+        let (lunatic, module) = runtime();
+        let config = ProcessConfig {
+            fuel: Some(10_000),
+            fuel_slice: Some(100),
+            ..Default::default()
+        };
+        let process = lunatic.start_with(module, "spin", &[], &config).unwrap();
+        let process_id = process.id();
+        assert!(matches!(process.await, Err(LunaticError::OutOfFuel)));
+        assert!(matches!(
+            lunatic.status(process_id),
+            Some(ProcessStatus::OutOfFuel)
+        ));
+
+        let process = lunatic.start_with(module, "nop", &[], &config).unwrap();
+        assert!(process.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn module_fuel_budget_applies_to_its_processes() {
+        let (lunatic, _) = runtime();
+        let config = ProcessConfig {
+            fuel: Some(10_000),
+            ..Default::default()
+        };
+        let module = lunatic.load_with(GUEST, config).unwrap();
+        let process = lunatic.start(module, "spin", &[]).unwrap();
+        assert!(matches!(process.await, Err(LunaticError::OutOfFuel)));
+    }
+
+    #[tokio::test]
+    async fn unload_refuses_while_in_use() {
+        let (lunatic, module) = runtime();
+        let process = lunatic.start(module, "spin", &[]).unwrap();
+        assert!(matches!(
+            lunatic.unload(module),
+            Err(LunaticError::ModuleInUse { processes: 1, .. })
+        ));
+
+        lunatic.kill(process.id());
+        lunatic.unload(module).unwrap();
+        assert_eq!(lunatic.module_processes(module), None);
+        assert!(matches!(
+            lunatic.start(module, "spin", &[]),
+            Err(LunaticError::UnknownModule(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unload_when_idle_waits_for_processes() {
+        let (lunatic, module) = runtime();
+        let process = lunatic.start(module, "spin", &[]).unwrap();
+        assert!(!lunatic.unload_when_idle(module).unwrap());
+        assert!(matches!(
+            lunatic.start(module, "spin", &[]),
+            Err(LunaticError::ModuleUnloading(_))
+        ));
+        assert_eq!(lunatic.module_processes(module), Some(1));
+
+        lunatic.kill(process.id());
+        assert_eq!(lunatic.module_processes(module), None);
+    }
+
+    #[tokio::test]
+    async fn history_drops_the_oldest_ended_processes() {
+        let (lunatic, runner) = Lunatic::builder().history(1).build().unwrap();
+        tokio::spawn(runner);
+        let module = lunatic.load(GUEST).unwrap();
+        let first = lunatic.start(module, "nop", &[]).unwrap();
+        let first_id = first.id();
+        first.await.unwrap();
+        let second = lunatic.start(module, "nop", &[]).unwrap();
+        let second_id = second.id();
+        second.await.unwrap();
+
+        assert!(lunatic.status(first_id).is_none());
+        assert!(matches!(
+            lunatic.status(second_id),
+            Some(ProcessStatus::Finished(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_out_of_bounds_traps() {
+        let (lunatic, _) = runtime();
+        let module = lunatic
+            .load(
+                r#"
+                (module
+                    (import "host" "send" (func $send (param i64 i32 i32) (result i32)))
+                    (memory (export "memory") 1)
+                    (func (export "flood")
+                        (drop (call $send (i64.const 0) (i32.const 0) (i32.const -1)))))
+                "#,
+            )
+            .unwrap();
+        match lunatic.start(module, "flood", &[]).unwrap().await {
+            Err(LunaticError::Trap { message, .. }) => {
+                assert!(message.contains("out of bounds"), "{}", message)
+            }
+            result => panic!("expected a trap, got {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_names_point_to_their_process() {
+        let (lunatic, module) = runtime();
+        let first = lunatic.start(module, "wait", &[]).unwrap();
+        let second = lunatic.start(module, "wait", &[]).unwrap();
+        lunatic.register("a", first.id()).unwrap();
+        lunatic.register("b", first.id()).unwrap();
+        assert_eq!(lunatic.whereis("a"), Some(first.id()));
+        assert_eq!(lunatic.whereis("b"), Some(first.id()));
+        assert!(matches!(
+            lunatic.register("a", second.id()),
+            Err(LunaticError::NameTaken { process_id, .. }) if process_id == first.id()
+        ));
+
+        assert_eq!(lunatic.unregister("a"), Some(first.id()));
+        assert_eq!(lunatic.whereis("a"), None);
+        assert_eq!(lunatic.unregister("a"), None);
+        lunatic.register("a", second.id()).unwrap();
+        assert_eq!(lunatic.whereis("a"), Some(second.id()));
+    }
+
+    #[tokio::test]
+    async fn names_are_removed_when_the_process_ends() {
+        let (lunatic, module) = runtime();
+        let first = lunatic.start(module, "wait", &[]).unwrap();
+        let second = lunatic.start(module, "wait", &[]).unwrap();
+        lunatic.register("a", first.id()).unwrap();
+        lunatic.register("b", first.id()).unwrap();
+        // Moved to the second process, the first one ending must not remove it.
+        lunatic.unregister("b");
+        lunatic.register("b", second.id()).unwrap();
+
+        assert!(lunatic.kill(first.id()));
+        assert_eq!(lunatic.whereis("a"), None);
+        assert_eq!(lunatic.whereis("b"), Some(second.id()));
+        assert!(matches!(
+            lunatic.register("c", first.id()),
+            Err(LunaticError::UnknownProcess(_))
+        ));
+        assert_eq!(lunatic.whereis("c"), None);
+    }
+
+    /// A module whose exports return `version`.
+    fn versioned(version: i32) -> String {
+        format!(
+            r#"
+            (module
+                (import "host" "receive" (func $receive (param i32 i32 i64) (result i64)))
+                (import "host" "message_kind" (func $message_kind (result i32)))
+                (memory (export "memory") 1)
+                (func (export "version") (result i32) (i32.const {0}))
+                (func (export "wait") (result i32)
+                    (drop (call $receive (i32.const 0) (i32.const 64) (i64.const -1)))
+                    (i32.const {0}))
+                (func (export "kind") (result i32)
+                    (drop (call $receive (i32.const 0) (i32.const 64) (i64.const -1)))
+                    (call $message_kind)))
+            "#,
+            version
+        )
+    }
+
+    #[tokio::test]
+    async fn upgrade_only_affects_new_processes() {
+        let (lunatic, _) = runtime();
+        let module = lunatic.load(versioned(1)).unwrap();
+        let running = lunatic.start(module, "wait", &[]).unwrap();
+        wait_until_running(&lunatic, running.id()).await;
+
+        assert_eq!(lunatic.upgrade(module, versioned(2), false).unwrap(), 2);
+        let results = lunatic
+            .start(module, "version", &[])
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(results[0].unwrap_i32(), 2);
+        // Without `notify` nothing is sent, the process keeps its old instance.
+        lunatic.send(running.id(), "stop").unwrap();
+        assert_eq!(running.await.unwrap()[0].unwrap_i32(), 1);
+    }
+
+    #[tokio::test]
+    async fn upgrade_notifies_running_processes() {
+        let (lunatic, _) = runtime();
+        let module = lunatic.load(versioned(1)).unwrap();
+        let running = lunatic.start(module, "kind", &[]).unwrap();
+        wait_until_running(&lunatic, running.id()).await;
+
+        lunatic.upgrade(module, versioned(2), true).unwrap();
+        let results = running.await.unwrap();
+        assert_eq!(results[0].unwrap_i32() as u32, mailbox::UPGRADE_MESSAGE);
+    }
+
+    #[tokio::test]
+    async fn upgrade_of_unloading_module_fails() {
+        let (lunatic, _) = runtime();
+        let module = lunatic.load(versioned(1)).unwrap();
+        let running = lunatic.start(module, "wait", &[]).unwrap();
+        assert!(!lunatic.unload_when_idle(module).unwrap());
+
+        assert!(matches!(
+            lunatic.upgrade(module, versioned(2), false),
+            Err(LunaticError::ModuleUnloading(id)) if id == module
+        ));
+        lunatic.send(running.id(), "stop").unwrap();
+        assert_eq!(running.await.unwrap()[0].unwrap_i32(), 1);
+    }
+
+    #[tokio::test]
+    async fn module_info_reads_sections() {
+        let (lunatic, _) = runtime();
+        let module = lunatic
+            .load(
+                r#"
+                (module $guest
+                    (memory 1 2)
+                    (table 3 funcref)
+                    (func $run (export "run"))
+                    (@custom "meta" "hello"))
+                "#,
+            )
+            .unwrap();
+        let info = lunatic.module_info(module).unwrap();
+        assert_eq!(info.name.as_deref(), Some("guest"));
+        assert_eq!(info.memories[0].minimum(), 1);
+        assert_eq!(info.memories[0].maximum(), Some(2));
+        assert_eq!(info.tables[0].minimum(), 3);
+        assert_eq!(info.function_names.get(&0).map(String::as_str), Some("run"));
+        let meta = info
+            .custom_sections
+            .iter()
+            .find(|section| section.name == "meta")
+            .unwrap();
+        assert_eq!(meta.data, b"hello");
+        assert_eq!(meta.len, 5);
+    }
+
+    #[tokio::test]
+    async fn module_info_cuts_off_large_custom_sections() {
+        let (lunatic, _) = runtime();
+        // A custom section named "big" with 100000 bytes of contents.
+        let mut wasm = b"\0asm\x01\0\0\0\0".to_vec();
+        wasm.extend_from_slice(&[0xa4, 0x8d, 0x06, 3]);
+        wasm.extend_from_slice(b"big");
+        wasm.resize(wasm.len() + 100_000, 7);
+        let module = lunatic.load(wasm).unwrap();
+        let info = lunatic.module_info(module).unwrap();
+        assert_eq!(info.custom_sections[0].name, "big");
+        assert_eq!(info.custom_sections[0].len, 100_000);
+        assert_eq!(info.custom_sections[0].data, vec![7; 64 * 1024]);
+    }
+}
@@ -0,0 +1,120 @@
+use wasmtime::ResourceLimiter;
+
+/// Size of a wasm page in bytes.
+const PAGE_SIZE: usize = 0x10000;
+
+/// Maximum number of instances in a store, if not configured. It's wasmtime's default.
+const DEFAULT_INSTANCES: usize = 10_000;
+
+/// Caps on the resources a single process can use.
+///
+/// Unset fields fall back to the limits of the module, then to the runtime's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessLimits {
+    /// Maximum size of each linear memory, in wasm pages of 64 KiB.
+    pub memory_pages: Option<u32>,
+    /// Maximum number of elements of each table.
+    pub table_elements: Option<u32>,
+    /// Maximum number of instances in the process' store, 10000 by default.
+    pub instances: Option<usize>,
+}
+
+impl ProcessLimits {
+    /// Fills the unset fields from `fallback`.
+    pub(crate) fn or(&self, fallback: &ProcessLimits) -> ProcessLimits {
+        ProcessLimits {
+            memory_pages: self.memory_pages.or(fallback.memory_pages),
+            table_elements: self.table_elements.or(fallback.table_elements),
+            instances: self.instances.or(fallback.instances),
+        }
+    }
+}
+
+/// Enforces `ProcessLimits` on a store and remembers the first breach, so it
+/// can be reported as the reason the process ended.
+pub struct Limiter {
+    limits: ProcessLimits,
+    exceeded: Option<String>,
+}
+
+impl Limiter {
+    pub fn new(limits: ProcessLimits) -> Self {
+        Self {
+            limits,
+            exceeded: None,
+        }
+    }
+
+    pub fn limits(&self) -> ProcessLimits {
+        self.limits
+    }
+
+    pub fn exceeded(&self) -> Option<&str> {
+        self.exceeded.as_deref()
+    }
+
+    fn exceed(&mut self, reason: String) -> bool {
+        self.exceeded.get_or_insert(reason);
+        false
+    }
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        let pages = desired / PAGE_SIZE;
+        Ok(match self.limits.memory_pages {
+            Some(limit) if pages > limit as usize => self.exceed(format!(
+                "memory of {} pages exceeds the limit of {} pages",
+                pages, limit
+            )),
+            _ => true,
+        })
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        Ok(match self.limits.table_elements {
+            Some(limit) if desired > limit as usize => self.exceed(format!(
+                "table of {} elements exceeds the limit of {} elements",
+                desired, limit
+            )),
+            _ => true,
+        })
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances.unwrap_or(DEFAULT_INSTANCES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn or_merges_field_by_field() {
+        let process = ProcessLimits {
+            table_elements: Some(10),
+            ..Default::default()
+        };
+        let module = ProcessLimits {
+            memory_pages: Some(2),
+            table_elements: Some(100),
+            instances: None,
+        };
+        let merged = process.or(&module);
+        assert_eq!(merged.memory_pages, Some(2));
+        assert_eq!(merged.table_elements, Some(10));
+        assert_eq!(merged.instances, None);
+        assert_eq!(Limiter::new(merged).instances(), DEFAULT_INSTANCES);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+use tokio::sync::Notify;
+
+use crate::{ModuleId, ProcessId, ProcessStatus, HOST};
+
+/// Kind of message as reported to guests by `host.message_kind`.
+pub const DATA_MESSAGE: u32 = 0;
+pub const DOWN_MESSAGE: u32 = 1;
+pub const UPGRADE_MESSAGE: u32 = 2;
+
+/// A message as it sits in a mailbox.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Data {
+        from: ProcessId,
+        data: Vec<u8>,
+    },
+    /// A monitored process ended.
+    Down {
+        process_id: ProcessId,
+        reason: ProcessStatus,
+    },
+    /// The module of the process was upgraded, new processes run `version`.
+    Upgrade {
+        module_id: ModuleId,
+        version: u32,
+    },
+}
+
+impl Message {
+    pub fn kind(&self) -> u32 {
+        match self {
+            Message::Data { .. } => DATA_MESSAGE,
+            Message::Down { .. } => DOWN_MESSAGE,
+            Message::Upgrade { .. } => UPGRADE_MESSAGE,
+        }
+    }
+
+    /// The sending process, for `Down` messages the process that ended and
+    /// for `Upgrade` messages the host.
+    pub fn sender(&self) -> ProcessId {
+        match self {
+            Message::Data { from, .. } => *from,
+            Message::Down { process_id, .. } => *process_id,
+            Message::Upgrade { .. } => HOST,
+        }
+    }
+
+    /// Bytes handed to a guest receiving the message.
+    ///
+    /// Guests get the reason of a `Down` message and the new version of an
+    /// `Upgrade` message as text.
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            Message::Data { data, .. } => data.clone(),
+            Message::Down { reason, .. } => reason.to_string().into_bytes(),
+            Message::Upgrade { version, .. } => version.to_string().into_bytes(),
+        }
+    }
+}
+
+/// Queue of messages sent to a process (or to the host), waiting to be received.
+#[derive(Default)]
+pub struct Mailbox {
+    messages: Mutex<VecDeque<Message>>,
+    notify: Notify,
+}
+
+impl Mailbox {
+    pub fn push(&self, message: Message) {
+        self.messages.lock().unwrap().push_back(message);
+        self.notify.notify_one();
+    }
+
+    /// Puts a message back at the front, e.g. if the receiver couldn't take it yet.
+    pub fn push_front(&self, message: Message) {
+        self.messages.lock().unwrap().push_front(message);
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next message, at most `timeout` if one is given.
+    pub async fn pop(&self, timeout: Option<Duration>) -> Option<Message> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.next()).await.ok(),
+            None => Some(self.next().await),
+        }
+    }
+
+    async fn next(&self) -> Message {
+        loop {
+            let message = self.messages.lock().unwrap().pop_front();
+            if let Some(message) = message {
+                return message;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
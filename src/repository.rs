@@ -0,0 +1,179 @@
+use std::{cmp::Ordering, collections::HashMap, path::Path, sync::RwLock};
+
+use crate::ModuleId;
+
+/// Version given to modules loaded from files without `@version` in their name.
+pub(crate) const DEFAULT_VERSION: &str = "0";
+
+/// Loaded modules by name and version.
+#[derive(Default)]
+pub(crate) struct Repository {
+    // Versions of every name, sorted from oldest to latest.
+    modules: RwLock<HashMap<String, Vec<(String, ModuleId)>>>,
+}
+
+impl Repository {
+    /// Finds the module for `name@version`, or the latest version of `name`.
+    pub(crate) fn resolve(&self, spec: &str) -> Option<ModuleId> {
+        let (name, version) = split(spec);
+        let modules = self.modules.read().unwrap();
+        let versions = modules.get(name)?;
+        match version {
+            Some(version) => versions
+                .iter()
+                .find(|(v, _)| compare(v, version) == Ordering::Equal)
+                .map(|(_, id)| *id),
+            None => versions.last().map(|(_, id)| *id),
+        }
+    }
+
+    pub(crate) fn contains(&self, name: &str, version: &str) -> bool {
+        self.modules
+            .read()
+            .unwrap()
+            .get(name)
+            .is_some_and(|versions| {
+                versions
+                    .iter()
+                    .any(|(v, _)| compare(v, version) == Ordering::Equal)
+            })
+    }
+
+    /// Adds a module, returns `false` if `name@version` is already taken.
+    pub(crate) fn insert(&self, name: &str, version: &str, module_id: ModuleId) -> bool {
+        let mut modules = self.modules.write().unwrap();
+        let versions = modules.entry(name.to_string()).or_default();
+        match versions.binary_search_by(|(v, _)| compare(v, version)) {
+            Ok(_) => false,
+            Err(index) => {
+                versions.insert(index, (version.to_string(), module_id));
+                true
+            }
+        }
+    }
+
+    /// Removes an unloaded module, the previous version becomes the latest.
+    pub(crate) fn remove(&self, module_id: ModuleId) {
+        let mut modules = self.modules.write().unwrap();
+        for versions in modules.values_mut() {
+            versions.retain(|(_, id)| *id != module_id);
+        }
+        modules.retain(|_, versions| !versions.is_empty());
+    }
+
+    /// Versions of `name` from oldest to latest.
+    pub(crate) fn versions(&self, name: &str) -> Vec<(String, ModuleId)> {
+        self.modules
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Splits `name@version` into its parts, the version is optional.
+pub(crate) fn split(spec: &str) -> (&str, Option<&str>) {
+    match spec.find('@') {
+        Some(at) => (&spec[..at], Some(&spec[at + 1..])),
+        None => (spec, None),
+    }
+}
+
+/// Checks that a name and version can be told apart in `name@version`.
+pub(crate) fn is_valid(name: &str, version: &str) -> bool {
+    !name.is_empty() && !version.is_empty() && !name.contains('@') && !version.contains('@')
+}
+
+/// Name and version of a module file called `name@version.wasm` or
+/// `name@version.wat`, `None` for other files.
+pub(crate) fn file_spec(path: &Path) -> Option<(String, String)> {
+    match path.extension()?.to_str()? {
+        "wasm" | "wat" => (),
+        _ => return None,
+    }
+    let (name, version) = split(path.file_stem()?.to_str()?);
+    Some((
+        name.to_string(),
+        version.unwrap_or(DEFAULT_VERSION).to_string(),
+    ))
+}
+
+/// Orders versions by their dot separated components, numerically where both
+/// components are numbers, so `1.10` is newer than `1.9`.
+fn compare(a: &str, b: &str) -> Ordering {
+    let mut a = a.split('.');
+    let mut b = b.split('.');
+    loop {
+        let ordering = match (a.next(), b.next()) {
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (None, None) => return Ordering::Equal,
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_versions_numerically() {
+        assert_eq!(compare("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare("1.2", "1.2.0"), Ordering::Less);
+        assert_eq!(compare("2.0", "2.0"), Ordering::Equal);
+        assert_eq!(compare("1.0-beta", "1.0-alpha"), Ordering::Greater);
+    }
+
+    #[test]
+    fn splits_specs() {
+        assert_eq!(split("echo@1.2"), ("echo", Some("1.2")));
+        assert_eq!(split("echo"), ("echo", None));
+        assert_eq!(split("echo@"), ("echo", Some("")));
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert!(is_valid("echo", "1.0"));
+        assert!(!is_valid("echo", ""));
+        assert!(!is_valid("", "1.0"));
+        assert!(!is_valid("echo@1", "0"));
+    }
+
+    #[test]
+    fn reads_names_of_module_files() {
+        let spec = |path: &str| file_spec(Path::new(path));
+        assert_eq!(
+            spec("modules/echo@1.2.wasm"),
+            Some(("echo".to_string(), "1.2".to_string()))
+        );
+        assert_eq!(
+            spec("echo.wat"),
+            Some(("echo".to_string(), DEFAULT_VERSION.to_string()))
+        );
+        assert_eq!(spec("echo@1.2.txt"), None);
+        assert_eq!(spec("echo"), None);
+    }
+
+    #[test]
+    fn resolves_the_latest_version() {
+        let repository = Repository::default();
+        assert!(repository.insert("echo", "1.9", 1));
+        assert!(repository.insert("echo", "1.10", 2));
+        assert!(!repository.insert("echo", "1.9", 4));
+        assert_eq!(repository.resolve("echo"), Some(2));
+        assert_eq!(repository.resolve("echo@1.9"), Some(1));
+        assert_eq!(repository.resolve("echo@2"), None);
+        assert_eq!(repository.resolve("echo@"), None);
+
+        repository.remove(2);
+        assert_eq!(repository.resolve("echo"), Some(1));
+    }
+}
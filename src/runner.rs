@@ -0,0 +1,386 @@
+use std::{
+    panic::AssertUnwindSafe,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use futures_util::FutureExt;
+use tokio::{
+    sync::{mpsc, oneshot, OwnedSemaphorePermit},
+    time::{self, Instant},
+};
+
+use crate::{LunaticInner, ProcessId, ProcessStatus, QueuedProcess};
+
+/// What `start` does when the spawn queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Admission {
+    /// `start_async` waits for room in the queue, `start` fails.
+    #[default]
+    Wait,
+    /// `start` and `start_async` fail right away.
+    TryFail,
+    /// The process is created but ends as `ProcessStatus::Rejected` without running.
+    Reject,
+}
+
+/// Snapshot of the spawn queue, returned by `Lunatic::queue_metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueMetrics {
+    /// Processes waiting for the runner, including ones killed while queued.
+    pub queued: usize,
+    pub queue_capacity: usize,
+    pub running: usize,
+    /// Maximum number of processes running at once, `None` if unlimited.
+    pub max_running: Option<usize>,
+    /// Processes turned away so far because the queue was full.
+    pub rejected: u64,
+}
+
+/// What happened to the processes still alive when the runtime shut down,
+/// the runner future resolves to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Processes that ended on their own while the runtime waited for them.
+    pub finished: usize,
+    /// Processes killed after the wait, including ones that were still queued.
+    pub killed: usize,
+}
+
+/// Stops a runtime, obtained with `Lunatic::shutdown_handle`.
+pub struct ShutdownHandle {
+    inner: Arc<LunaticInner>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(inner: Arc<LunaticInner>) -> Self {
+        acquire(&inner);
+        Self { inner }
+    }
+
+    /// Stops accepting new processes, waits up to `wait` for the running ones
+    /// to end and kills the rest, with `None` right away.
+    ///
+    /// Returns `false` if the shutdown was already requested.
+    pub fn shutdown(&self, wait: Option<Duration>) -> bool {
+        stop(&self.inner, Stop::Shutdown(wait))
+    }
+}
+
+impl Clone for ShutdownHandle {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
+}
+
+impl Drop for ShutdownHandle {
+    fn drop(&mut self) {
+        release(&self.inner);
+    }
+}
+
+/// Why the runner stops taking processes from the queue.
+pub(crate) enum Stop {
+    /// Requested through a `ShutdownHandle`, with the time to wait for the
+    /// running processes.
+    Shutdown(Option<Duration>),
+    /// Every `Lunatic` and `ShutdownHandle` was dropped. Processes that were
+    /// already started still run, the runner resolves once they all ended.
+    Released,
+}
+
+/// Counts a new `Lunatic` or `ShutdownHandle`.
+pub(crate) fn acquire(lunatic: &LunaticInner) {
+    lunatic.handles.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Drops the count of a `Lunatic` or `ShutdownHandle`, stopping the runner
+/// once the last one is gone.
+pub(crate) fn release(lunatic: &LunaticInner) {
+    if lunatic.handles.fetch_sub(1, Ordering::AcqRel) == 1 {
+        stop(lunatic, Stop::Released);
+    }
+}
+
+/// Asks the runner to stop, returns `false` if it was already asked to.
+fn stop(lunatic: &LunaticInner, stop: Stop) -> bool {
+    match lunatic.shutdown.lock().unwrap().take() {
+        Some(shutdown) => {
+            // Set right away, not once the runner gets to it, so `start` fails
+            // from now on.
+            lunatic.accepting.store(false, Ordering::SeqCst);
+            shutdown.send(stop).ok();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Spawns a task for every queued process until the runner is asked to stop.
+///
+/// With a limit on running processes, a process stays queued until one of
+/// the running ones ends.
+pub(crate) async fn run(
+    lunatic: Arc<LunaticInner>,
+    mut receiver: mpsc::Receiver<QueuedProcess>,
+    mut shutdown: oneshot::Receiver<Stop>,
+) -> ShutdownSummary {
+    let stop = loop {
+        let slot = match &lunatic.running_slots {
+            Some(slots) => tokio::select! {
+                slot = slots.clone().acquire_owned() => slot.ok(),
+                stop = &mut shutdown => break stop.unwrap_or(Stop::Shutdown(None)),
+            },
+            None => None,
+        };
+        tokio::select! {
+            Some(process) = receiver.recv() => spawn(&lunatic, process, slot),
+            stop = &mut shutdown => break stop.unwrap_or(Stop::Shutdown(None)),
+        }
+    };
+
+    // From here on `start` fails.
+    lunatic.accepting.store(false, Ordering::SeqCst);
+    receiver.close();
+    let running = alive(&lunatic).len();
+    let mut killed = 0;
+    let deadline = match stop {
+        Stop::Shutdown(wait) => {
+            // Processes that made it into the queue never run.
+            while let Some(process) = receiver.recv().await {
+                if lunatic.finish(process.process_id, ProcessStatus::Killed) {
+                    killed += 1;
+                }
+            }
+            Some(Instant::now() + wait.unwrap_or_default())
+        }
+        Stop::Released => {
+            while let Some(process) = receiver.recv().await {
+                let slot = match &lunatic.running_slots {
+                    Some(slots) => slots.clone().acquire_owned().await.ok(),
+                    None => None,
+                };
+                spawn(&lunatic, process, slot);
+            }
+            None
+        }
+    };
+
+    loop {
+        let ended = lunatic.ended.notified();
+        if alive(&lunatic).is_empty() {
+            break;
+        }
+        match deadline {
+            Some(deadline) => tokio::select! {
+                _ = ended => (),
+                _ = time::sleep_until(deadline) => break,
+            },
+            None => ended.await,
+        }
+    }
+    for process_id in alive(&lunatic) {
+        if lunatic.finish(process_id, ProcessStatus::Killed) {
+            killed += 1;
+        }
+    }
+    ShutdownSummary {
+        finished: running.saturating_sub(killed),
+        killed,
+    }
+}
+
+fn spawn(lunatic: &Arc<LunaticInner>, process: QueuedProcess, slot: Option<OwnedSemaphorePermit>) {
+    let process_id = process.process_id;
+    if !lunatic.set_running(process_id) {
+        return;
+    }
+    let runner = lunatic.clone();
+    let running = Running::new(lunatic.clone(), slot);
+    let task = tokio::spawn(async move {
+        let _running = running;
+        // A panicking host function or extension function must still end
+        // the process, or it would count as running forever.
+        let status = AssertUnwindSafe(runner.clone().run(process))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(ProcessStatus::from_panic);
+        runner.finish(process_id, status);
+    });
+    // If the process got killed before the task was stored, nobody else
+    // is going to abort it.
+    match lunatic.processes.get_mut(&process_id) {
+        Some(mut process) if !process.status.is_finished() => process.task = Some(task),
+        _ => task.abort(),
+    }
+}
+
+/// Counts a process as running and holds its slot for as long as its task
+/// exists, including when the task gets aborted.
+struct Running {
+    lunatic: Arc<LunaticInner>,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Running {
+    fn new(lunatic: Arc<LunaticInner>, slot: Option<OwnedSemaphorePermit>) -> Self {
+        lunatic.running.fetch_add(1, Ordering::Relaxed);
+        Self {
+            lunatic,
+            _slot: slot,
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.lunatic.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Processes that are still queued or running.
+fn alive(lunatic: &LunaticInner) -> Vec<ProcessId> {
+    lunatic
+        .processes
+        .iter()
+        .filter(|process| !process.status.is_finished())
+        .map(|process| *process.key())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lunatic, LunaticError, ModuleId};
+
+    const GUEST: &str = r#"(module (func (export "nop")))"#;
+
+    /// A runtime with room for a single queued process, whose runner isn't
+    /// polled yet.
+    fn runtime(
+        admission: Admission,
+    ) -> (
+        Lunatic,
+        impl std::future::Future<Output = ShutdownSummary>,
+        ModuleId,
+    ) {
+        let (lunatic, runner) = Lunatic::builder()
+            .queue_capacity(1)
+            .admission(admission)
+            .build()
+            .unwrap();
+        let module = lunatic.load(GUEST).unwrap();
+        (lunatic, runner, module)
+    }
+
+    #[tokio::test]
+    async fn wait_admission_waits_for_room() {
+        let (lunatic, runner, module) = runtime(Admission::Wait);
+        let first = lunatic.start(module, "nop", &[]).unwrap();
+        assert!(matches!(
+            lunatic.start(module, "nop", &[]),
+            Err(LunaticError::QueueFull)
+        ));
+
+        let waiting = lunatic.start_async(module, "nop", &[]);
+        tokio::pin!(waiting);
+        assert!(time::timeout(Duration::from_millis(50), &mut waiting)
+            .await
+            .is_err());
+        tokio::spawn(runner);
+        let second = waiting.await.unwrap();
+        first.await.unwrap();
+        second.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn try_fail_admission_fails_right_away() {
+        let (lunatic, _runner, module) = runtime(Admission::TryFail);
+        lunatic.start(module, "nop", &[]).unwrap();
+        assert!(matches!(
+            lunatic.start_async(module, "nop", &[]).await,
+            Err(LunaticError::QueueFull)
+        ));
+        assert_eq!(lunatic.queue_metrics().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn reject_admission_ends_process_as_rejected() {
+        let (lunatic, _runner, module) = runtime(Admission::Reject);
+        lunatic.start(module, "nop", &[]).unwrap();
+        let rejected = lunatic.start_async(module, "nop", &[]).await.unwrap();
+        let process_id = rejected.id();
+        assert!(matches!(rejected.await, Err(LunaticError::Rejected)));
+        assert!(matches!(
+            lunatic.status(process_id),
+            Some(ProcessStatus::Rejected)
+        ));
+        assert_eq!(lunatic.queue_metrics().queued, 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_accepting_right_away() {
+        let (lunatic, _runner) = Lunatic::new().unwrap();
+        let module = lunatic.load(GUEST).unwrap();
+        assert!(lunatic.shutdown_handle().shutdown(None));
+        assert!(matches!(
+            lunatic.start(module, "nop", &[]),
+            Err(LunaticError::ShuttingDown)
+        ));
+        assert!(!lunatic.shutdown_handle().shutdown(None));
+    }
+
+    #[tokio::test]
+    async fn panicking_host_function_ends_process() {
+        let (lunatic, runner) = Lunatic::builder()
+            .host_functions(|linker| {
+                linker.func_wrap("env", "boom", || -> i32 { panic!("boom") })?;
+                Ok(())
+            })
+            .build()
+            .unwrap();
+        let runner = tokio::spawn(runner);
+        let module = lunatic
+            .load(
+                r#"(module
+                    (import "env" "boom" (func $boom (result i32)))
+                    (func (export "run") call $boom drop))"#,
+            )
+            .unwrap();
+        let process = lunatic.start(module, "run", &[]).unwrap();
+        let process_id = process.id();
+
+        assert!(matches!(process.await, Err(LunaticError::Trap { .. })));
+        match lunatic.status(process_id) {
+            Some(ProcessStatus::Trapped { message, .. }) => assert!(message.contains("boom")),
+            status => panic!("unexpected status {:?}", status),
+        }
+        drop(lunatic);
+        time::timeout(Duration::from_secs(5), runner)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn runner_resolves_once_all_handles_are_dropped() {
+        let (lunatic, runner) = Lunatic::new().unwrap();
+        let mut runner = tokio::spawn(runner);
+        let module = lunatic.load(GUEST).unwrap();
+        let process = lunatic.start(module, "nop", &[]).unwrap();
+        let shutdown = lunatic.shutdown_handle();
+
+        drop(lunatic);
+        assert!(time::timeout(Duration::from_millis(50), &mut runner)
+            .await
+            .is_err());
+
+        drop(shutdown);
+        let summary = time::timeout(Duration::from_secs(5), runner)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.killed, 0);
+        process.await.unwrap();
+    }
+}
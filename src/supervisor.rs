@@ -0,0 +1,430 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+use wasmtime::Val;
+
+use crate::{Lunatic, LunaticError, LunaticInner, ModuleId, ProcessId, ProcessStatus, Result};
+
+/// Which children get restarted when one of them ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Only the child that ended.
+    OneForOne,
+    /// All children.
+    OneForAll,
+    /// The child that ended and all children started after it.
+    RestForOne,
+}
+
+/// When a child is restarted after it ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// Always.
+    Permanent,
+    /// Only if it failed.
+    Transient,
+    /// Never, it's also not restarted with its siblings.
+    Temporary,
+}
+
+/// Describes how to start a child process.
+#[derive(Debug, Clone)]
+pub struct ChildSpec {
+    pub module_id: ModuleId,
+    pub function: String,
+    pub params: Vec<Val>,
+    pub restart: Restart,
+}
+
+impl ChildSpec {
+    pub fn new(module_id: ModuleId, function: &str, params: &[Val]) -> Self {
+        Self {
+            module_id,
+            function: function.to_string(),
+            params: params.to_vec(),
+            restart: Restart::Permanent,
+        }
+    }
+
+    pub fn restart(mut self, restart: Restart) -> Self {
+        self.restart = restart;
+        self
+    }
+}
+
+struct Child {
+    spec: ChildSpec,
+    // Process currently running for this child.
+    process_id: Option<ProcessId>,
+    // Bumped on every start, to tell exits of replaced processes apart.
+    generation: u64,
+}
+
+/// Starts a set of children and restarts them according to a `Strategy`.
+///
+/// If children need to be restarted more than `max_restarts` times within
+/// `period`, the supervisor gives up, kills the remaining children and fails.
+pub struct Supervisor {
+    runtime: Arc<LunaticInner>,
+    strategy: Strategy,
+    max_restarts: usize,
+    period: Duration,
+    children: Vec<Child>,
+}
+
+impl Supervisor {
+    pub fn new(lunatic: &Lunatic, strategy: Strategy) -> Self {
+        Self {
+            runtime: lunatic.inner.clone(),
+            strategy,
+            max_restarts: 3,
+            period: Duration::from_secs(5),
+            children: Vec::new(),
+        }
+    }
+
+    /// Sets the maximum restart intensity, 3 restarts in 5 seconds by default.
+    pub fn intensity(mut self, max_restarts: usize, period: Duration) -> Self {
+        self.max_restarts = max_restarts;
+        self.period = period;
+        self
+    }
+
+    /// Adds a child, children are started in the order they were added.
+    pub fn child(mut self, spec: ChildSpec) -> Self {
+        self.children.push(Child {
+            spec,
+            process_id: None,
+            generation: 0,
+        });
+        self
+    }
+
+    /// Starts all children and supervises them.
+    ///
+    /// Resolves once no child is running anymore and none needs a restart, or
+    /// with an error if the restart intensity was exceeded. A supervisor
+    /// without children resolves right away.
+    pub async fn run(mut self) -> Result<()> {
+        if self.children.is_empty() {
+            // Nothing would ever close the channel below.
+            return Ok(());
+        }
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        for index in 0..self.children.len() {
+            if let Err(error) = self.start_child(index, &sender).await {
+                self.terminate_all();
+                return Err(error);
+            }
+        }
+
+        let mut restarts = VecDeque::new();
+        while let Some((index, generation, status)) = receiver.recv().await {
+            let child = &mut self.children[index];
+            if child.generation != generation {
+                // Exit of a process the supervisor replaced itself.
+                continue;
+            }
+            child.process_id = None;
+            let restart = match child.spec.restart {
+                Restart::Permanent => true,
+                Restart::Transient => status.is_failure(),
+                Restart::Temporary => false,
+            };
+            if !restart {
+                if self.children.iter().all(|child| child.process_id.is_none()) {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            let now = Instant::now();
+            restarts.push_back(now);
+            while let Some(at) = restarts.front() {
+                if now.duration_since(*at) <= self.period {
+                    break;
+                }
+                restarts.pop_front();
+            }
+            if restarts.len() > self.max_restarts {
+                self.terminate_all();
+                return Err(LunaticError::RestartIntensity {
+                    child: index,
+                    status,
+                    max_restarts: self.max_restarts,
+                    period: self.period,
+                });
+            }
+
+            let siblings = match self.strategy {
+                Strategy::OneForOne => index..index + 1,
+                Strategy::OneForAll => 0..self.children.len(),
+                Strategy::RestForOne => index..self.children.len(),
+            };
+            let mut restarted = Vec::new();
+            for sibling in siblings {
+                let child = &mut self.children[sibling];
+                if sibling != index && child.process_id.is_none() {
+                    continue;
+                }
+                if let Some(process_id) = child.process_id.take() {
+                    self.runtime.finish(process_id, ProcessStatus::Killed);
+                }
+                if sibling == index || child.spec.restart != Restart::Temporary {
+                    restarted.push(sibling);
+                }
+            }
+            for sibling in restarted {
+                if let Err(error) = self.start_child(sibling, &sender).await {
+                    self.terminate_all();
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts the process of a child, waiting for room in the spawn queue if
+    /// the runtime's admission policy allows it.
+    async fn start_child(
+        &mut self,
+        index: usize,
+        sender: &mpsc::UnboundedSender<(usize, u64, ProcessStatus)>,
+    ) -> Result<()> {
+        let child = &mut self.children[index];
+        let handle = self
+            .runtime
+            .start_async(
+                child.spec.module_id,
+                &child.spec.function,
+                &child.spec.params,
+                &Default::default(),
+            )
+            .await?;
+        let process_id = handle.id();
+        child.generation += 1;
+        child.process_id = Some(process_id);
+
+        let generation = child.generation;
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let status = handle.status().await;
+            // The supervisor may have given up already.
+            sender.send((index, generation, status)).ok();
+        });
+        Ok(())
+    }
+
+    fn terminate_all(&mut self) {
+        for child in self.children.iter_mut() {
+            if let Some(process_id) = child.process_id.take() {
+                self.runtime.finish(process_id, ProcessStatus::Killed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::FutureExt;
+
+    const GUEST: &str = r#"
+        (module
+            (import "host" "register" (func $register (param i32 i32) (result i32)))
+            (import "host" "receive" (func $receive (param i32 i32 i64) (result i64)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "w0w1w2")
+            (func (export "nop"))
+            (func (export "fail") unreachable)
+            ;; Registers as "w<index>" and fails on the first message.
+            (func (export "worker") (param $index i64)
+                (drop (call $register
+                    (i32.wrap_i64 (i64.mul (local.get $index) (i64.const 2)))
+                    (i32.const 2)))
+                (drop (call $receive (i32.const 16) (i32.const 16) (i64.const -1)))
+                unreachable))
+    "#;
+
+    fn runtime() -> (Lunatic, ModuleId) {
+        let (lunatic, runner) = Lunatic::builder().build().unwrap();
+        tokio::spawn(runner);
+        let module = lunatic.load(GUEST).unwrap();
+        (lunatic, module)
+    }
+
+    fn workers(lunatic: &Lunatic, module: ModuleId, strategy: Strategy) -> Supervisor {
+        (0..3).fold(Supervisor::new(lunatic, strategy), |supervisor, index| {
+            supervisor.child(ChildSpec::new(module, "worker", &[Val::I64(index)]))
+        })
+    }
+
+    /// Waits until a process other than `old` is registered under `name`.
+    async fn registered(lunatic: &Lunatic, name: &str, old: Option<ProcessId>) -> ProcessId {
+        for _ in 0..5000 {
+            match lunatic.whereis(name) {
+                Some(process_id) if Some(process_id) != old => return process_id,
+                _ => tokio::time::sleep(Duration::from_millis(1)).await,
+            }
+        }
+        panic!("no new process registered as {}", name);
+    }
+
+    /// Lets `w1` fail and returns the processes registered afterwards.
+    async fn fail_second_worker(
+        lunatic: &Lunatic,
+        strategy: Strategy,
+    ) -> (Vec<ProcessId>, Vec<ProcessId>) {
+        let mut before = Vec::new();
+        for name in &["w0", "w1", "w2"] {
+            before.push(registered(lunatic, name, None).await);
+        }
+        lunatic.send(before[1], "fail").unwrap();
+        let restarted = match strategy {
+            Strategy::OneForOne => 1..2,
+            Strategy::OneForAll => 0..3,
+            Strategy::RestForOne => 1..3,
+        };
+        for index in restarted {
+            registered(lunatic, &format!("w{}", index), Some(before[index])).await;
+        }
+        let after = ["w0", "w1", "w2"]
+            .iter()
+            .map(|name| lunatic.whereis(name).unwrap())
+            .collect();
+        (before, after)
+    }
+
+    #[tokio::test]
+    async fn one_for_one_restarts_only_the_failed_child() {
+        let (lunatic, module) = runtime();
+        tokio::spawn(workers(&lunatic, module, Strategy::OneForOne).run());
+
+        let (before, after) = fail_second_worker(&lunatic, Strategy::OneForOne).await;
+        assert_eq!(before[0], after[0]);
+        assert_ne!(before[1], after[1]);
+        assert_eq!(before[2], after[2]);
+    }
+
+    #[tokio::test]
+    async fn one_for_all_restarts_all_children() {
+        let (lunatic, module) = runtime();
+        tokio::spawn(workers(&lunatic, module, Strategy::OneForAll).run());
+
+        let (before, after) = fail_second_worker(&lunatic, Strategy::OneForAll).await;
+        assert!(before
+            .iter()
+            .zip(&after)
+            .all(|(before, after)| before != after));
+    }
+
+    #[tokio::test]
+    async fn rest_for_one_restarts_the_later_children() {
+        let (lunatic, module) = runtime();
+        tokio::spawn(workers(&lunatic, module, Strategy::RestForOne).run());
+
+        let (before, after) = fail_second_worker(&lunatic, Strategy::RestForOne).await;
+        assert_eq!(before[0], after[0]);
+        assert_ne!(before[1], after[1]);
+        assert_ne!(before[2], after[2]);
+    }
+
+    #[tokio::test]
+    async fn transient_children_are_only_restarted_after_failures() {
+        let (lunatic, module) = runtime();
+        let supervisor = Supervisor::new(&lunatic, Strategy::OneForOne)
+            .child(ChildSpec::new(module, "nop", &[]).restart(Restart::Transient));
+        assert!(supervisor.run().await.is_ok());
+
+        let supervisor = Supervisor::new(&lunatic, Strategy::OneForOne)
+            .child(ChildSpec::new(module, "fail", &[]).restart(Restart::Transient));
+        let result = supervisor.run().await;
+        assert!(matches!(
+            result,
+            Err(LunaticError::RestartIntensity { child: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn temporary_children_are_never_restarted() {
+        let (lunatic, module) = runtime();
+        let supervisor = Supervisor::new(&lunatic, Strategy::OneForOne)
+            .child(ChildSpec::new(module, "fail", &[]).restart(Restart::Temporary));
+        assert!(supervisor.run().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn supervisor_gives_up_after_too_many_restarts() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let counter = started.clone();
+        let (lunatic, runner) = Lunatic::builder()
+            .extension(move |_, _| counter.fetch_add(1, Ordering::SeqCst))
+            .build()
+            .unwrap();
+        tokio::spawn(runner);
+        let module = lunatic.load(GUEST).unwrap();
+        let supervisor = Supervisor::new(&lunatic, Strategy::OneForOne)
+            .intensity(2, Duration::from_secs(60))
+            .child(ChildSpec::new(module, "nop", &[]));
+
+        let result = supervisor.run().await;
+        assert!(matches!(
+            result,
+            Err(LunaticError::RestartIntensity {
+                child: 0,
+                status: ProcessStatus::Finished(_),
+                max_restarts: 2,
+                ..
+            })
+        ));
+        // The first start and two restarts, the third exit exceeds the limit.
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn restarts_outside_the_period_do_not_count() {
+        let (lunatic, module) = runtime();
+        let supervisor = Supervisor::new(&lunatic, Strategy::OneForOne)
+            .intensity(1, Duration::from_millis(20))
+            .child(ChildSpec::new(module, "worker", &[Val::I64(0)]));
+        let mut supervisor = tokio::spawn(supervisor.run());
+
+        let mut process_id = registered(&lunatic, "w0", None).await;
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            lunatic.send(process_id, "fail").unwrap();
+            process_id = registered(&lunatic, "w0", Some(process_id)).await;
+        }
+        assert!((&mut supervisor).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn supervisor_without_children_resolves() {
+        let (lunatic, _) = runtime();
+        let supervisor = Supervisor::new(&lunatic, Strategy::OneForOne);
+        let result = tokio::time::timeout(Duration::from_secs(5), supervisor.run()).await;
+        assert!(matches!(result, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn children_wait_for_room_in_the_queue() {
+        let (lunatic, runner) = Lunatic::builder().queue_capacity(1).build().unwrap();
+        tokio::spawn(runner);
+        let module = lunatic.load(r#"(module (func (export "nop")))"#).unwrap();
+        let child = ChildSpec::new(module, "nop", &[]).restart(Restart::Temporary);
+        let supervisor = Supervisor::new(&lunatic, Strategy::OneForOne)
+            .child(child.clone())
+            .child(child.clone())
+            .child(child);
+
+        let result = tokio::spawn(supervisor.run()).await.unwrap();
+        assert!(result.is_ok());
+    }
+}